@@ -0,0 +1,226 @@
+// src/account.rs
+
+use crate::{
+    error::{AppError, FieldError},
+    extract::AppJson,
+    response,
+    session::SessionUser,
+    sqlite::{Databases, SQLITE_DATETIME},
+    users::UserRow,
+    AppState,
+};
+use axum::{extract::State, response::Response};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+
+/// Consecutive failed sign-ins that lock an account, unless `LOGIN_MAX_FAILURES` is set.
+const DEFAULT_MAX_FAILED_LOGINS: i64 = 5;
+/// Seconds an account stays locked, unless `LOGIN_LOCKOUT_SECS` is set.
+const DEFAULT_LOCKOUT_SECS: i64 = 900;
+
+/// `status_reason` recorded when an account is locked automatically.
+const LOCKOUT_REASON: &str = "too_many_failed_logins";
+
+/// The value of `users.status`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    Active,
+    /// Blocked by an admin, until `status_until` or until lifted.
+    Suspended,
+    /// Blocked after too many failed sign-ins, until `status_until`.
+    Locked,
+    /// Created but the email address has not been verified yet.
+    PendingVerification,
+}
+
+impl AccountStatus {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active" => Some(AccountStatus::Active),
+            "suspended" => Some(AccountStatus::Suspended),
+            "locked" => Some(AccountStatus::Locked),
+            "pending_verification" => Some(AccountStatus::PendingVerification),
+            _ => None,
+        }
+    }
+
+    /// SQL condition on `users` matching accounts currently in this status,
+    /// treating suspensions and locks whose `status_until` has passed as active.
+    pub fn filter_sql(self) -> &'static str {
+        match self {
+            AccountStatus::Active => {
+                "(status = 'active' OR (status IN ('suspended', 'locked') \
+                AND status_until <= datetime('now')))"
+            }
+            AccountStatus::Suspended => {
+                "(status = 'suspended' AND (status_until IS NULL OR status_until > datetime('now')))"
+            }
+            AccountStatus::Locked => {
+                "(status = 'locked' AND (status_until IS NULL OR status_until > datetime('now')))"
+            }
+            AccountStatus::PendingVerification => "status = 'pending_verification'",
+        }
+    }
+}
+
+/// The status that applies to a user right now, as returned in user responses.
+#[derive(Serialize, Debug)]
+pub struct StatusInfo {
+    pub status: AccountStatus,
+    pub reason: Option<String>,
+    pub until: Option<String>,
+}
+
+/// Resolves the stored status, expiring suspensions and locks whose
+/// `status_until` has passed. Unknown values fail closed as suspended.
+pub fn current_status(user: &UserRow) -> StatusInfo {
+    let status = AccountStatus::parse(&user.status).unwrap_or(AccountStatus::Suspended);
+    let now = Utc::now().format(SQLITE_DATETIME).to_string();
+    let expired = matches!(status, AccountStatus::Suspended | AccountStatus::Locked)
+        && user.status_until.as_deref().is_some_and(|until| until <= now.as_str());
+    if expired {
+        return StatusInfo {
+            status: AccountStatus::Active,
+            reason: None,
+            until: None,
+        };
+    }
+    StatusInfo {
+        status,
+        reason: user.status_reason.clone(),
+        until: user.status_until.clone(),
+    }
+}
+
+/// Rejects users that may not sign in or use an existing session.
+/// Every authentication path must call this after identifying the user.
+pub fn ensure_can_authenticate(user: &UserRow) -> Result<(), AppError> {
+    let status = current_status(user);
+    let (code, message) = match status.status {
+        AccountStatus::Active => return Ok(()),
+        AccountStatus::Suspended => ("account.suspended", "This account has been suspended."),
+        AccountStatus::Locked => (
+            "account.locked",
+            "This account is temporarily locked after too many failed sign-in attempts.",
+        ),
+        AccountStatus::PendingVerification => (
+            "account.pending_verification",
+            "This account's email address has not been verified yet.",
+        ),
+    };
+    Err(AppError::AccountInactive {
+        code,
+        message: message.to_string(),
+        details: serde_json::to_value(&status).ok(),
+    })
+}
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|val| *val > 0)
+        .unwrap_or(default)
+}
+
+/// Counts a failed sign-in and locks the account once `LOGIN_MAX_FAILURES`
+/// is reached. Returns whether this failure locked it.
+pub async fn record_failed_login(db: &Databases, user_id: &str) -> Result<bool, AppError> {
+    let failures: i64 = sqlx::query_scalar(
+        "UPDATE users SET failed_logins = failed_logins + 1 WHERE user_id = ? RETURNING failed_logins",
+    )
+    .bind(user_id)
+    .fetch_one(&db.account)
+    .await?;
+    if failures < env_i64("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILED_LOGINS) {
+        return Ok(false);
+    }
+
+    // Only an active account (or one whose lock has expired) is locked, so an
+    // admin suspension is never replaced by a shorter lock.
+    let result = sqlx::query(
+        "UPDATE users
+        SET status = 'locked', status_reason = ?,
+            status_until = datetime('now', '+' || ? || ' seconds'), failed_logins = 0
+        WHERE user_id = ? AND status IN ('active', 'locked')",
+    )
+    .bind(LOCKOUT_REASON)
+    .bind(env_i64("LOGIN_LOCKOUT_SECS", DEFAULT_LOCKOUT_SECS))
+    .bind(user_id)
+    .execute(&db.account)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Resets the failure counter after a successful sign-in and clears any
+/// suspension or lock that has already expired.
+pub async fn clear_failed_logins(db: &Databases, user_id: &str) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE users
+        SET failed_logins = 0,
+            status = CASE WHEN status IN ('suspended', 'locked') THEN 'active' ELSE status END,
+            status_reason = CASE WHEN status IN ('suspended', 'locked') THEN NULL ELSE status_reason END,
+            status_until = CASE WHEN status IN ('suspended', 'locked') THEN NULL ELSE status_until END
+        WHERE user_id = ?
+            AND (failed_logins != 0
+                OR (status IN ('suspended', 'locked') AND status_until <= datetime('now')))",
+    )
+    .bind(user_id)
+    .execute(&db.account)
+    .await?;
+    Ok(())
+}
+
+/// Moves a user out of `pending_verification` once an email is verified.
+pub async fn activate_pending(db: &Databases, user_id: &str) -> Result<(), AppError> {
+    sqlx::query(
+        "UPDATE users SET status = 'active' WHERE user_id = ? AND status = 'pending_verification'",
+    )
+    .bind(user_id)
+    .execute(&db.account)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetLocale {
+    locale: Option<String>,
+}
+
+/// `PUT /v1/account/locale`
+///
+/// Sets the signed-in user's preferred language for emails. `null` clears it,
+/// so emails follow the browser's `Accept-Language` again.
+pub async fn set_locale(
+    State(state): State<AppState>,
+    session: SessionUser,
+    AppJson(body): AppJson<SetLocale>,
+) -> Result<Response, AppError> {
+    let templates = state.mailer.templates();
+    let locale = match body.locale.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(tag) => Some(templates.match_locale(tag).ok_or_else(|| {
+            AppError::Validation(vec![FieldError::new(
+                "locale",
+                "unsupported_locale",
+                format!(
+                    "Supported locales: {}.",
+                    templates.locales().collect::<Vec<_>>().join(", ")
+                ),
+            )])
+        })?),
+    };
+
+    sqlx::query(
+        "UPDATE users SET locale = ? WHERE user_id = ? AND deleted_at IS NULL",
+    )
+    .bind(&locale)
+    .bind(&session.user_id)
+    .execute(&state.db.account)
+    .await?;
+
+    Ok(response::success(Some(json!({ "locale": locale }))))
+}
@@ -0,0 +1,308 @@
+// src/audit.rs
+
+use crate::{
+    error::{AppError, FieldError},
+    extract::AppQuery,
+    logging::{log, LogLevel},
+    rbac::{perm, Authorized},
+    request_id, response, AppState,
+};
+use axum::{extract::State, response::Response};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, QueryBuilder, Sqlite};
+use std::{net::IpAddr, sync::Arc};
+use tokio::sync::Mutex;
+
+/// `prev_hash` of the first event in the chain.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+/// A security event about to be appended to the audit log.
+#[derive(Debug)]
+pub struct AuditEvent {
+    actor: String,
+    action: &'static str,
+    target_user: Option<String>,
+    ip: Option<String>,
+    request_id: Option<String>,
+    outcome: Outcome,
+}
+
+impl AuditEvent {
+    /// Creates an event. The current request ID is captured here, so events
+    /// built inside a request keep it even when recorded from a spawned task.
+    pub fn new(actor: impl Into<String>, action: &'static str, outcome: Outcome) -> Self {
+        Self {
+            actor: actor.into(),
+            action,
+            target_user: None,
+            ip: None,
+            request_id: request_id::current(),
+            outcome,
+        }
+    }
+
+    pub fn target_user(mut self, user_id: impl Into<String>) -> Self {
+        self.target_user = Some(user_id.into());
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip.map(|ip| ip.to_string());
+        self
+    }
+}
+
+/// A stored audit event, as returned by the query endpoint.
+#[derive(Serialize, Debug, sqlx::FromRow)]
+struct StoredEvent {
+    id: i64,
+    created_at: String,
+    actor: String,
+    action: String,
+    target_user: Option<String>,
+    ip: Option<String>,
+    request_id: Option<String>,
+    outcome: String,
+    prev_hash: String,
+    hash: String,
+}
+
+/// The fields covered by an event's hash, in a fixed order.
+#[derive(Serialize)]
+struct HashedFields<'a> {
+    created_at: &'a str,
+    actor: &'a str,
+    action: &'a str,
+    target_user: Option<&'a str>,
+    ip: Option<&'a str>,
+    request_id: Option<&'a str>,
+    outcome: &'a str,
+}
+
+fn chain_hash(prev_hash: &str, fields: &HashedFields) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(fields).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+/// Append-only, hash-chained log of security events stored in `audit.sqlite`.
+///
+/// Each row's `hash` covers its own fields and the previous row's `hash`, so
+/// editing or removing a row breaks every hash after it. Truncating the tail
+/// of the chain is not detectable from the table alone.
+#[derive(Clone)]
+pub struct Audit {
+    pool: Pool<Sqlite>,
+    // Serializes appends so that two events never chain off the same row.
+    append_lock: Arc<Mutex<()>>,
+}
+
+impl Audit {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self {
+            pool,
+            append_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub async fn record(&self, event: AuditEvent) -> anyhow::Result<()> {
+        let _guard = self.append_lock.lock().await;
+
+        let prev_hash: String =
+            sqlx::query_scalar("SELECT hash FROM audit_events ORDER BY id DESC LIMIT 1")
+                .fetch_optional(&self.pool)
+                .await?
+                .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+        let created_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let hash = chain_hash(
+            &prev_hash,
+            &HashedFields {
+                created_at: &created_at,
+                actor: &event.actor,
+                action: event.action,
+                target_user: event.target_user.as_deref(),
+                ip: event.ip.as_deref(),
+                request_id: event.request_id.as_deref(),
+                outcome: event.outcome.as_str(),
+            },
+        );
+
+        sqlx::query(
+            "INSERT INTO audit_events
+                (created_at, actor, action, target_user, ip, request_id, outcome, prev_hash, hash)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&created_at)
+        .bind(&event.actor)
+        .bind(event.action)
+        .bind(&event.target_user)
+        .bind(&event.ip)
+        .bind(&event.request_id)
+        .bind(event.outcome.as_str())
+        .bind(&prev_hash)
+        .bind(&hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records an event in the background. Failures are logged, never returned,
+    /// so auditing cannot block or fail the request that triggered it.
+    pub fn spawn_record(&self, event: AuditEvent) {
+        let audit = self.clone();
+        tokio::spawn(async move {
+            let action = event.action;
+            if let Err(e) = audit.record(event).await {
+                log(
+                    LogLevel::Error,
+                    &format!("Failed to record audit event {}: {:#}", action, e),
+                );
+            }
+        });
+    }
+
+    /// Walks the whole chain and returns the ID of the first event whose
+    /// hash or link to its predecessor does not match, if any.
+    async fn verify(&self) -> anyhow::Result<(i64, Option<i64>)> {
+        let events: Vec<StoredEvent> = sqlx::query_as("SELECT * FROM audit_events ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut expected_prev = GENESIS_HASH.to_string();
+        for (checked, event) in events.iter().enumerate() {
+            let recomputed = chain_hash(
+                &event.prev_hash,
+                &HashedFields {
+                    created_at: &event.created_at,
+                    actor: &event.actor,
+                    action: &event.action,
+                    target_user: event.target_user.as_deref(),
+                    ip: event.ip.as_deref(),
+                    request_id: event.request_id.as_deref(),
+                    outcome: &event.outcome,
+                },
+            );
+            if event.prev_hash != expected_prev || event.hash != recomputed {
+                return Ok((checked as i64, Some(event.id)));
+            }
+            expected_prev = recomputed;
+        }
+        Ok((events.len() as i64, None))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EventQuery {
+    user: Option<String>,
+    action: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+fn parse_time(field: &str, value: Option<&str>) -> Result<Option<String>, FieldError> {
+    value
+        .map(|raw| {
+            DateTime::parse_from_rfc3339(raw)
+                .map(|t| t.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Millis, true))
+                .map_err(|_| FieldError::new(field, "invalid_timestamp", "Expected an RFC 3339 timestamp."))
+        })
+        .transpose()
+}
+
+/// Lists audit events, newest first, filtered by target user, action and time range.
+/// Pass the returned `next_before_id` as `before_id` to fetch the next page.
+pub async fn list_events(
+    State(state): State<AppState>,
+    _auth: Authorized<perm::AuditRead>,
+    AppQuery(query): AppQuery<EventQuery>,
+) -> Result<Response, AppError> {
+    let since = parse_time("since", query.since.as_deref());
+    let until = parse_time("until", query.until.as_deref());
+    let (since, until) = match (since, until) {
+        (Ok(since), Ok(until)) => (since, until),
+        (since, until) => {
+            let errors = [since.err(), until.err()].into_iter().flatten().collect();
+            return Err(AppError::Validation(errors));
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut builder = QueryBuilder::<Sqlite>::new("SELECT * FROM audit_events WHERE 1 = 1");
+    if let Some(user) = &query.user {
+        builder.push(" AND target_user = ").push_bind(user);
+    }
+    if let Some(action) = &query.action {
+        builder.push(" AND action = ").push_bind(action);
+    }
+    if let Some(since) = since {
+        builder.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = until {
+        builder.push(" AND created_at < ").push_bind(until);
+    }
+    if let Some(before_id) = query.before_id {
+        builder.push(" AND id < ").push_bind(before_id);
+    }
+    builder.push(" ORDER BY id DESC LIMIT ").push_bind(limit);
+
+    let events: Vec<StoredEvent> = builder
+        .build_query_as()
+        .fetch_all(&state.audit.pool)
+        .await?;
+    let next_before_id = if events.len() as i64 == limit {
+        events.last().map(|e| e.id)
+    } else {
+        None
+    };
+
+    Ok(response::success(Some(json!({
+        "events": events,
+        "next_before_id": next_before_id,
+    }))))
+}
+
+/// Re-computes the hash chain and reports whether the log has been tampered with.
+pub async fn verify_chain(
+    State(state): State<AppState>,
+    _auth: Authorized<perm::AuditRead>,
+) -> Result<Response, AppError> {
+    let (checked, first_invalid_id) = state.audit.verify().await?;
+    if let Some(id) = first_invalid_id {
+        log(
+            LogLevel::Error,
+            &format!("Audit log verification failed at event {}.", id),
+        );
+    }
+
+    Ok(response::success(Some(json!({
+        "valid": first_invalid_id.is_none(),
+        "checked": checked,
+        "first_invalid_id": first_invalid_id,
+    }))))
+}
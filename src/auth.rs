@@ -1,25 +1,68 @@
 // src/auth.rs
 
-use crate::{response, AppState};
+use crate::{
+    audit::{AuditEvent, Outcome},
+    error::AppError,
+    rate_limiting::ClientIp,
+    rbac::Role,
+    session::{self, SessionUser},
+    AppState,
+};
 use axum::{
-    extract::{Request, State},
-    http::{header, StatusCode},
+    extract::{FromRequestParts, Request, State},
+    http::header,
     middleware::Next,
     response::Response,
 };
 use std::env;
 
+/// The authenticated caller, attached to the response for access logging.
+#[derive(Clone, Debug)]
+pub struct Principal(pub String);
+
+/// Who made an authenticated request, stored as a request extension for
+/// `rbac::Authorized` and handlers.
+#[derive(Clone, Debug)]
+pub enum Caller {
+    /// The API token, or anyone in dev mode. Holds every permission.
+    Token { principal: &'static str },
+    /// A signed-in user, limited to the permissions of their role.
+    User(SessionUser),
+}
+
+impl Caller {
+    /// The actor recorded in audit events.
+    pub fn actor(&self) -> &str {
+        match self {
+            Caller::Token { principal } => principal,
+            Caller::User(user) => &user.user_id,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        match self {
+            Caller::Token { .. } => Role::Owner,
+            Caller::User(user) => user.role(),
+        }
+    }
+}
+
+/// Authenticates admin API requests, either with the API token as a `Bearer`
+/// token or with a user session (see `session::SessionUser`). Which
+/// permissions the caller holds is checked by the handlers.
+///
+/// Browsers attach the session cookie to any same-site request, so a
+/// cookie-authenticated request that can change state must also come from
+/// this site's own pages (see `is_same_origin`).
 pub async fn auth_middleware(
     State(state): State<AppState>,
     req: Request,
     next: Next,
-) -> Response {
+) -> Result<Response, AppError> {
     // Check if we're in dev mode
-    if let Ok(mode) = env::var("MODE") {
-        if mode == "dev" {
-            // Skip authentication in dev mode
-            return next.run(req).await;
-        }
+    if env::var("MODE").is_ok_and(|mode| mode == "dev") {
+        // Skip authentication in dev mode
+        return Ok(run_as(req, next, Caller::Token { principal: "dev" }).await);
     }
 
     // Get the API_TOKEN from the shared application state.
@@ -34,27 +77,88 @@ pub async fn auth_middleware(
         .get(header::AUTHORIZATION)
         .and_then(|header| header.to_str().ok());
 
-    match auth_header {
-        Some(header_value) => {
-            if let Some(token) = header_value.strip_prefix("Bearer ") {
-                if token == api_token {
-                    // Token is valid, proceed with the request.
-                    next.run(req).await
-                } else {
-                    // Token is invalid.
-                    response::error(StatusCode::UNAUTHORIZED, "Invalid authentication token.")
-                }
-            } else {
-                // Header format is incorrect.
-                response::error(
-                    StatusCode::UNAUTHORIZED,
-                    "Invalid authorization header format.",
-                )
+    let has_bearer = match auth_header {
+        Some(header_value) => match header_value.strip_prefix("Bearer ") {
+            Some(token) if token == api_token => {
+                // Token is valid, proceed with the request.
+                return Ok(run_as(req, next, Caller::Token { principal: "admin" }).await);
             }
-        }
-        None => {
-            // 'Authorization' header is missing.
-            response::error(StatusCode::UNAUTHORIZED, "Authorization header is missing.")
-        }
-    }
-}
\ No newline at end of file
+            Some(_) => true,
+            // Header format is incorrect.
+            None => {
+                return Err(reject(&state, &req, "auth.admin_token", AppError::AuthHeaderMalformed));
+            }
+        },
+        None => false,
+    };
+
+    // Not the API token: try it, or the session cookie, as a user session.
+    let has_cookie = session::cookie_value(req.headers(), session::SESSION_COOKIE).is_some();
+    let (mut parts, body) = req.into_parts();
+    let session = SessionUser::from_request_parts(&mut parts, &state).await;
+    let req = Request::from_parts(parts, body);
+    let (action, error) = match session {
+        Ok(_) if !has_bearer && !req.method().is_safe() && !is_same_origin(&req) => (
+            "auth.csrf",
+            AppError::Forbidden {
+                code: "auth.origin_mismatch",
+                message: "Requests authenticated by the session cookie must come from this site."
+                    .to_string(),
+            },
+        ),
+        Ok(user) => return Ok(run_as(req, next, Caller::User(user)).await),
+        Err(AppError::SessionInvalid) if has_bearer => ("auth.admin_token", AppError::TokenInvalid),
+        Err(AppError::SessionInvalid) if has_cookie => ("auth.session", AppError::SessionInvalid),
+        // 'Authorization' header and session cookie are both missing.
+        Err(AppError::SessionInvalid) => ("auth.admin_token", AppError::AuthHeaderMissing),
+        Err(e @ AppError::Internal(_)) => return Err(e),
+        // The session is live but its account may not authenticate.
+        Err(e) => ("auth.session", e),
+    };
+    Err(reject(&state, &req, action, error))
+}
+
+/// Whether the request's `Origin`, or failing that its `Referer`, names the
+/// host it was sent to. Browsers send `Origin` with every state-changing
+/// request, and a sibling subdomain, although same-site for cookies, is a
+/// different origin.
+fn is_same_origin(req: &Request) -> bool {
+    let headers = req.headers();
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+    else {
+        return false;
+    };
+    let Some(source) = headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    source
+        .split_once("://")
+        .and_then(|(_, rest)| rest.split(['/', '?', '#']).next())
+        .is_some_and(|authority| authority.eq_ignore_ascii_case(host))
+}
+
+async fn run_as(mut req: Request, next: Next, caller: Caller) -> Response {
+    let principal = Principal(caller.actor().to_string());
+    req.extensions_mut().insert(caller);
+    let mut response = next.run(req).await;
+    response.extensions_mut().insert(principal);
+    response
+}
+
+/// Records a failed authentication under `action`, which names what was
+/// tried: the API token, a user session, or the CSRF origin check.
+fn reject(state: &AppState, req: &Request, action: &'static str, error: AppError) -> AppError {
+    state.metrics.record_auth_failure(&error);
+    let ip = req.extensions().get::<ClientIp>().map(|ip| ip.0);
+    state
+        .audit
+        .spawn_record(AuditEvent::new("anonymous", action, Outcome::Failure).ip(ip));
+    error
+}
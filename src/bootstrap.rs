@@ -1,18 +1,103 @@
 // src/bootstrap.rs
 
+use crate::logging::{log, LogLevel};
 use axum::Router;
-use fancy_log::{log, LogLevel};
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{net::TcpListener, sync::watch};
 
-pub async fn run(app: Router) -> Result<(), Box<dyn std::error::Error>>
+/// Default address of the admin listener. It serves `/metrics`, so it is
+/// only reachable from the host unless `ADMIN_LISTEN_ADDR` says otherwise.
+const DEFAULT_ADMIN_ADDR: &str = "127.0.0.1:33303";
+
+/// Default time between failing `/readyz` and closing the listeners, so load
+/// balancers can stop routing traffic first. Overridden by `SHUTDOWN_DRAIN_SECS`.
+const DEFAULT_DRAIN_SECS: u64 = 5;
+
+/// Binds both listeners, marks the server ready and serves until SIGINT or SIGTERM.
+pub async fn run(
+    app: Router,
+    admin_app: Router,
+    ready: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>>
 {
     let addr = SocketAddr::from(([0, 0, 0, 0], 33302));
     let listener = TcpListener::bind(&addr).await?;
     let info_message = "Listening on http://localhost:33302";
     log(LogLevel::Info, info_message);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    let admin_addr: SocketAddr = env::var("ADMIN_LISTEN_ADDR")
+        .unwrap_or_else(|_| DEFAULT_ADMIN_ADDR.to_string())
+        .parse()?;
+    let admin_listener = TcpListener::bind(&admin_addr).await?;
+    log(LogLevel::Info, &format!("Admin listener on http://{}", admin_addr));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(shutdown_signal(ready.clone(), shutdown_tx));
+
+    let mut public_shutdown = shutdown_rx.clone();
+    let public_server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        let _ = public_shutdown.wait_for(|stop| *stop).await;
+    });
+    let mut admin_shutdown = shutdown_rx;
+    let admin_server = axum::serve(admin_listener, admin_app.into_make_service())
+        .with_graceful_shutdown(async move {
+            let _ = admin_shutdown.wait_for(|stop| *stop).await;
+        });
 
+    ready.store(true, Ordering::Release);
+    tokio::try_join!(
+        async { public_server.await },
+        async { admin_server.await },
+    )?;
+
+    log(LogLevel::Info, "Server stopped.");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Waits for a termination signal, fails readiness, then tells the servers
+/// to stop once the drain period has passed.
+async fn shutdown_signal(ready: Arc<AtomicBool>, shutdown_tx: watch::Sender<bool>) {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    ready.store(false, Ordering::Release);
+    let drain_secs = env::var("SHUTDOWN_DRAIN_SECS")
+        .ok()
+        .and_then(|val| val.trim().parse().ok())
+        .unwrap_or(DEFAULT_DRAIN_SECS);
+    log(
+        LogLevel::Info,
+        &format!("Shutdown requested. Draining for {}s before closing listeners...", drain_secs),
+    );
+    tokio::time::sleep(Duration::from_secs(drain_secs)).await;
+    let _ = shutdown_tx.send(true);
+}
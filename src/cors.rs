@@ -1,16 +1,284 @@
 /* src/cors.rs */
 
+use crate::{
+    error::AppError,
+    logging::{log, LogLevel},
+};
 use axum::{
-    extract::Request,
-    http::{header, HeaderValue, Method, StatusCode},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
-use fancy_log::{log, LogLevel};
-use std::env;
+use std::{env, sync::Arc};
+
+/// A single entry of the origin allowlist.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OriginPattern {
+    /// Matches exactly one origin, e.g. `https://app.example.com`.
+    Exact(String),
+    /// Matches every subdomain of `suffix` over `scheme`, e.g. `https://*.example.com`.
+    /// The bare domain itself is not matched.
+    Wildcard {
+        scheme: String,
+        suffix: String,
+        port: Option<u16>,
+    },
+}
+
+/// A CORS policy for one group of routes.
+///
+/// Policies are attached per route group in `router::create_router`, so the
+/// same process can serve a credentialed SPA policy and a public one side by side.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    allowed_origins: Vec<OriginPattern>,
+    allow_localhost: bool,
+    any_origin: bool,
+    allow_credentials: bool,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<HeaderName>,
+    exposed_headers: Vec<HeaderName>,
+    max_age: Option<u64>,
+}
+
+const DEFAULT_ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+const DEFAULT_ALLOWED_HEADERS: &str = "Origin, X-Requested-With, Content-Type, Accept, Authorization";
+const DEFAULT_EXPOSED_HEADERS: &str = "Retry-After, X-RateLimit-Limit, X-RateLimit-Remaining";
+const DEFAULT_MAX_AGE: u64 = 600;
+
+/// The scheme, host and optional port of an `Origin` header value.
+struct ParsedOrigin<'a> {
+    scheme: &'a str,
+    host: &'a str,
+    port: Option<u16>,
+}
+
+impl CorsConfig {
+    /// Builds the credentialed SPA policy. The allowlist comes from
+    /// `CORS_ALLOWED_ORIGINS` (comma-separated), falling back to `VITE_GATEWAY`
+    /// when it is not set.
+    ///
+    /// Any `localhost` origin, over http or https, is accepted only when
+    /// `MODE=dev`. Otherwise a localhost entry in the list is matched exactly,
+    /// like any other origin.
+    ///
+    /// Preflight settings come from `CORS_ALLOWED_METHODS`, `CORS_ALLOWED_HEADERS`,
+    /// `CORS_EXPOSE_HEADERS` and `CORS_MAX_AGE` (seconds, `0` disables caching).
+    pub fn from_env() -> Self {
+        let raw = env::var("CORS_ALLOWED_ORIGINS")
+            .or_else(|_| env::var("VITE_GATEWAY"))
+            .unwrap_or_default();
+
+        let allowed_origins = parse_origin_list(&raw);
+        let allow_localhost = env::var("MODE").is_ok_and(|mode| mode == "dev");
+
+        if allowed_origins.is_empty() && !allow_localhost {
+            log(LogLevel::Error, "No CORS origins configured. Cross-origin requests will be rejected.");
+        }
+
+        let allowed_methods = list_from_env("CORS_ALLOWED_METHODS", DEFAULT_ALLOWED_METHODS)
+            .into_iter()
+            .filter_map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()).ok())
+            .collect();
+        let allowed_headers = header_names_from_env("CORS_ALLOWED_HEADERS", DEFAULT_ALLOWED_HEADERS);
+        let exposed_headers = header_names_from_env("CORS_EXPOSE_HEADERS", DEFAULT_EXPOSED_HEADERS);
+        let max_age = match env::var("CORS_MAX_AGE") {
+            Ok(val) => val.trim().parse::<u64>().ok().filter(|secs| *secs > 0),
+            Err(_) => Some(DEFAULT_MAX_AGE),
+        };
+
+        Self {
+            allowed_origins,
+            allow_localhost,
+            any_origin: false,
+            allow_credentials: true,
+            allowed_methods,
+            allowed_headers,
+            exposed_headers,
+            max_age,
+        }
+    }
+
+    /// Builds a read-only policy that allows any origin (`*`) without credentials,
+    /// suitable for static assets.
+    pub fn public() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allow_localhost: false,
+            any_origin: true,
+            allow_credentials: false,
+            allowed_methods: vec![Method::GET, Method::HEAD, Method::OPTIONS],
+            allowed_headers: vec![header::ACCEPT, header::CONTENT_TYPE, header::RANGE],
+            exposed_headers: Vec::new(),
+            max_age: Some(DEFAULT_MAX_AGE),
+        }
+    }
+
+    /// Returns whether the given `Origin` header value is on the allowlist.
+    pub fn is_origin_allowed(&self, origin: &str) -> bool {
+        if self.any_origin {
+            return true;
+        }
+
+        let Some(parsed) = ParsedOrigin::parse(origin) else {
+            return false;
+        };
+
+        if self.allow_localhost && parsed.is_localhost() {
+            return true;
+        }
+
+        self.allowed_origins
+            .iter()
+            .any(|pattern| pattern.matches(origin, &parsed))
+    }
+
+    fn is_method_allowed(&self, method: &str) -> bool {
+        self.allowed_methods.iter().any(|m| m.as_str() == method)
+    }
+
+    /// Checks a comma-separated `Access-Control-Request-Headers` value.
+    /// Header names are case-insensitive, so each one is lowercased first.
+    fn are_headers_allowed(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                let name = name.to_ascii_lowercase();
+                self.allowed_headers.iter().any(|h| h.as_str() == name)
+            })
+    }
+}
+
+/// Parses a comma-separated origin allowlist, skipping invalid entries.
+fn parse_origin_list(raw: &str) -> Vec<OriginPattern> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let pattern = OriginPattern::parse(entry);
+            if pattern.is_none() {
+                log(LogLevel::Warn, &format!("Ignoring invalid CORS origin pattern: {}", entry));
+            }
+            pattern
+        })
+        .collect()
+}
+
+/// Reads a comma-separated list from the environment, falling back to `default`.
+fn list_from_env(name: &str, default: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn header_names_from_env(name: &str, default: &str) -> Vec<HeaderName> {
+    list_from_env(name, default)
+        .into_iter()
+        .filter_map(|header| HeaderName::from_bytes(header.as_bytes()).ok())
+        .collect()
+}
+
+/// Joins values into a single comma-separated header value.
+fn join_header_value<T: AsRef<str>>(values: &[T]) -> Option<HeaderValue> {
+    let joined = values
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&joined).ok()
+}
+
+impl OriginPattern {
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim_end_matches('/').to_ascii_lowercase();
+        let parsed = ParsedOrigin::parse(&entry)?;
+
+        if let Some(suffix) = parsed.host.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains('*') {
+                return None;
+            }
+            return Some(OriginPattern::Wildcard {
+                scheme: parsed.scheme.to_string(),
+                suffix: format!(".{}", suffix),
+                port: parsed.port,
+            });
+        }
+
+        if parsed.host.contains('*') {
+            return None;
+        }
+        Some(OriginPattern::Exact(entry))
+    }
+
+    fn matches(&self, origin: &str, parsed: &ParsedOrigin) -> bool {
+        match self {
+            OriginPattern::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            OriginPattern::Wildcard {
+                scheme,
+                suffix,
+                port,
+            } => {
+                let host = parsed.host.to_ascii_lowercase();
+                parsed.scheme.eq_ignore_ascii_case(scheme)
+                    && parsed.port == *port
+                    && host.len() > suffix.len()
+                    && host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+impl<'a> ParsedOrigin<'a> {
+    fn parse(origin: &'a str) -> Option<Self> {
+        let (scheme, authority) = origin.split_once("://")?;
+        if !matches!(scheme.to_ascii_lowercase().as_str(), "http" | "https") {
+            return None;
+        }
+        if authority.is_empty() || authority.contains(['/', '?', '#', '@']) {
+            return None;
+        }
+
+        // IPv6 hosts are bracketed, so only split on a colon after the closing bracket.
+        let port_separator = match authority.rfind(']') {
+            Some(bracket) => authority[bracket..].find(':').map(|i| bracket + i),
+            None => authority.rfind(':'),
+        };
+        let (host, port) = match port_separator {
+            Some(i) => (&authority[..i], Some(authority[i + 1..].parse::<u16>().ok()?)),
+            None => (authority, None),
+        };
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Self { scheme, host, port })
+    }
+
+    fn is_localhost(&self) -> bool {
+        matches!(
+            self.host.to_ascii_lowercase().as_str(),
+            "localhost" | "127.0.0.1" | "[::1]"
+        )
+    }
+}
 
 /// This function intercepts requests to add CORS headers.
-pub async fn cors_middleware(req: Request, next: Next) -> Response {
+///
+/// Only a real preflight (an `OPTIONS` request carrying both `Origin` and
+/// `Access-Control-Request-Method`) is answered here; any other `OPTIONS`
+/// request is passed on to the router like a normal request.
+pub async fn cors_middleware(
+    State(config): State<Arc<CorsConfig>>,
+    req: Request,
+    next: Next,
+) -> Response {
     let origin_header = req
         .headers()
         .get(header::ORIGIN)
@@ -18,61 +286,205 @@ pub async fn cors_middleware(req: Request, next: Next) -> Response {
         .map(String::from);
 
     // --- Handle OPTIONS preflight requests ---
-    if req.method() == Method::OPTIONS {
-        let mut response = (StatusCode::OK, ()).into_response();
-        add_cors_headers(response.headers_mut(), origin_header.as_deref());
-        return response;
+    if req.method() == Method::OPTIONS
+        && let Some(origin) = origin_header.as_deref()
+        && let Some(requested_method) = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let requested_headers = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        return preflight_response(&config, origin, requested_method, requested_headers);
     }
 
     // --- Handle actual requests ---
     let mut response = next.run(req).await;
-    add_cors_headers(response.headers_mut(), origin_header.as_deref());
+    add_cors_headers(&config, response.headers_mut(), origin_header.as_deref());
+    response
+}
+
+/// Validates a preflight request against the policy and builds its response.
+fn preflight_response(
+    config: &CorsConfig,
+    origin: &str,
+    requested_method: &HeaderValue,
+    requested_headers: &str,
+) -> Response {
+    if !config.is_origin_allowed(origin) {
+        log(LogLevel::Warn, &format!("CORS preflight from untrusted origin blocked: {}", origin));
+        return forbidden_preflight("cors.origin_not_allowed", "Origin is not allowed.");
+    }
+
+    let method_allowed = requested_method
+        .to_str()
+        .is_ok_and(|method| config.is_method_allowed(method));
+    if !method_allowed {
+        return forbidden_preflight("cors.method_not_allowed", "Requested method is not allowed.");
+    }
+
+    if !config.are_headers_allowed(requested_headers) {
+        return forbidden_preflight("cors.headers_not_allowed", "Requested headers are not allowed.");
+    }
+
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let headers = response.headers_mut();
+    add_cors_headers(config, headers, Some(origin));
+
+    let methods: Vec<&str> = config.allowed_methods.iter().map(Method::as_str).collect();
+    if let Some(value) = join_header_value(&methods) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, value);
+    }
+    if let Some(value) = join_header_value(&config.allowed_headers) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+    }
+    if let Some(max_age) = config.max_age {
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+    }
+    headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Method"));
+    headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Headers"));
+    response
+}
+
+fn forbidden_preflight(code: &'static str, message: &str) -> Response {
+    let mut response = AppError::Forbidden {
+        code,
+        message: message.to_string(),
+    }
+    .into_response();
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Origin"));
     response
 }
 
 /// Helper function to add CORS headers to any response
-fn add_cors_headers(headers: &mut axum::http::HeaderMap, request_origin: Option<&str>) {
-    let vite_gateway = match env::var("VITE_GATEWAY") {
-        Ok(val) => val,
-        Err(_) => {
-            log(LogLevel::Error, "VITE_GATEWAY environment variable not set. CORS will not work.");
-            return;
-        }
+fn add_cors_headers(config: &CorsConfig, headers: &mut HeaderMap, request_origin: Option<&str>) {
+    if config.any_origin {
+        // A wildcard answer is the same for every origin, so no `Vary` is needed.
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        add_exposed_headers(config, headers);
+        return;
+    }
+
+    // The allowed origin is echoed back per request, so caches must key on it.
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+
+    let Some(origin_str) = request_origin else {
+        return;
     };
+    if !config.is_origin_allowed(origin_str) {
+        log(LogLevel::Warn, &format!("CORS request from untrusted origin blocked: {}", origin_str));
+        return;
+    }
 
-    let is_development = vite_gateway.contains("localhost");
+    if let Ok(value) = HeaderValue::from_str(origin_str) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    }
+    if config.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    add_exposed_headers(config, headers);
+}
 
-    if let Some(origin_str) = request_origin {
-        let origin_approved = if is_development {
-            // DEV MODE: Approve any request coming from a localhost origin.
-            if origin_str.starts_with("http://localhost:") {
-                true
-            } else {
-                false
-            }
-        } else {
-            origin_str == vite_gateway
-        };
+fn add_exposed_headers(config: &CorsConfig, headers: &mut HeaderMap) {
+    if !config.exposed_headers.is_empty()
+        && let Some(value) = join_header_value(&config.exposed_headers)
+    {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+    }
+}
 
-        if origin_approved {
-            if let Ok(value) = HeaderValue::from_str(origin_str) {
-                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
-            }
-        } else {
-            log(LogLevel::Warn, &format!("CORS request from untrusted origin blocked: {}", origin_str));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(origins: &str, allow_localhost: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: parse_origin_list(origins),
+            allow_localhost,
+            any_origin: false,
+            ..CorsConfig::public()
         }
     }
 
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("GET, POST, PUT, DELETE, OPTIONS"),
-    );
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_HEADERS,
-        HeaderValue::from_static("Origin, X-Requested-With, Content-Type, Accept, Authorization"),
-    );
-    headers.insert(
-        header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
-        HeaderValue::from_static("true"),
-    );
-}
\ No newline at end of file
+    #[test]
+    fn exact_origin_matches_only_itself() {
+        let cors = config("https://app.example.com/", false);
+        assert!(cors.is_origin_allowed("https://app.example.com"));
+        assert!(cors.is_origin_allowed("https://APP.example.com"));
+        assert!(!cors.is_origin_allowed("https://other.example.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com.evil.net"));
+        assert!(!cors.is_origin_allowed("https://app.example.com/path"));
+    }
+
+    #[test]
+    fn exact_origin_requires_same_scheme_and_port() {
+        let cors = config("https://app.example.com:8443", false);
+        assert!(cors.is_origin_allowed("https://app.example.com:8443"));
+        assert!(!cors.is_origin_allowed("https://app.example.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com:9443"));
+        assert!(!cors.is_origin_allowed("http://app.example.com:8443"));
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        let cors = config("https://*.example.com", false);
+        assert!(cors.is_origin_allowed("https://app.example.com"));
+        assert!(cors.is_origin_allowed("https://a.b.example.com"));
+        assert!(!cors.is_origin_allowed("https://example.com"));
+        assert!(!cors.is_origin_allowed("https://evilexample.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com.evil.net"));
+    }
+
+    #[test]
+    fn wildcard_requires_same_scheme_and_port() {
+        let cors = config("https://*.example.com, http://*.internal.test:8080", false);
+        assert!(!cors.is_origin_allowed("http://app.example.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com:8443"));
+        assert!(cors.is_origin_allowed("http://api.internal.test:8080"));
+        assert!(!cors.is_origin_allowed("http://api.internal.test"));
+        assert!(!cors.is_origin_allowed("https://api.internal.test:8080"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let patterns =
+            parse_origin_list("https://*, https://a.*.com, ftp://example.com, *, https://ok.example.com");
+        assert_eq!(
+            patterns,
+            vec![OriginPattern::Exact("https://ok.example.com".to_string())]
+        );
+    }
+
+    #[test]
+    fn configured_localhost_is_matched_exactly_outside_dev() {
+        let cors = config("http://localhost:5173", false);
+        assert!(cors.is_origin_allowed("http://localhost:5173"));
+        assert!(!cors.is_origin_allowed("http://localhost:3000"));
+        assert!(!cors.is_origin_allowed("https://localhost:5173"));
+        assert!(!cors.is_origin_allowed("http://127.0.0.1:5173"));
+    }
+
+    #[test]
+    fn dev_mode_allows_any_localhost_port_and_scheme() {
+        let cors = config("https://app.example.com", true);
+        assert!(cors.is_origin_allowed("http://localhost:3000"));
+        assert!(cors.is_origin_allowed("https://localhost:5173"));
+        assert!(cors.is_origin_allowed("http://127.0.0.1:8080"));
+        assert!(cors.is_origin_allowed("http://[::1]:8080"));
+        assert!(!cors.is_origin_allowed("http://localhost.evil.net"));
+    }
+
+    #[test]
+    fn malformed_origins_are_rejected() {
+        let cors = config("https://app.example.com", true);
+        assert!(!cors.is_origin_allowed("null"));
+        assert!(!cors.is_origin_allowed("app.example.com"));
+        assert!(!cors.is_origin_allowed("https://user@app.example.com"));
+        assert!(!cors.is_origin_allowed("https://app.example.com:notaport"));
+    }
+}
@@ -0,0 +1,552 @@
+// src/email.rs
+
+use crate::{
+    account,
+    audit::{AuditEvent, Outcome},
+    error::{AppError, FieldError},
+    extract::AppJson,
+    rate_limiting::ClientIp,
+    response,
+    session::SessionUser,
+    sqlite::SQLITE_DATETIME,
+    templates::EmailKind,
+    users, AppState,
+};
+use axum::{
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{NaiveDateTime, Utc};
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteExecutor;
+use std::env;
+use subtle::ConstantTimeEq;
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum length of an address, per RFC 5321.
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_LOCAL_PART_LEN: usize = 64;
+
+/// Emails a single account may have bound, verified or not.
+const MAX_EMAILS_PER_USER: i64 = 5;
+
+/// Seconds a code or link stays valid, unless `EMAIL_VERIFICATION_TTL_SECS` is set.
+const DEFAULT_VERIFICATION_TTL_SECS: i64 = 1800;
+/// Wrong codes accepted before the pending verification is voided.
+const MAX_CODE_ATTEMPTS: i64 = 5;
+/// Minimum seconds between two sends to the same address.
+const RESEND_INTERVAL_SECS: i64 = 60;
+/// Sends allowed per address within `SEND_WINDOW_SECS`.
+const MAX_SENDS_PER_WINDOW: i64 = 5;
+const SEND_WINDOW_SECS: i64 = 3600;
+
+/// Canonicalizes an address before it is used as the `email_users` key:
+/// Unicode NFC, lowercased domain and, when `EMAIL_GMAIL_NORMALIZATION` is
+/// enabled, Gmail's dot and plus-suffix rules. The local part otherwise keeps
+/// its case, since other providers may treat it as significant.
+pub fn normalize(raw: &str) -> Result<String, FieldError> {
+    let invalid = || FieldError::new("email", "invalid_email", "Not a valid email address.");
+    let email: String = raw.trim().nfc().collect();
+    if email.len() > MAX_EMAIL_LEN || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(invalid());
+    }
+    let (local, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+    let mut domain = domain.to_lowercase();
+    let mut local = local.to_string();
+    if local.is_empty()
+        || local.len() > MAX_LOCAL_PART_LEN
+        || local.contains('@')
+        || !domain.contains('.')
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+    {
+        return Err(invalid());
+    }
+
+    let gmail_rules = env::var("EMAIL_GMAIL_NORMALIZATION").is_ok_and(|val| val == "true");
+    if gmail_rules && (domain == "gmail.com" || domain == "googlemail.com") {
+        if let Some((base, _)) = local.split_once('+') {
+            local = base.to_string();
+        }
+        local = local.replace('.', "").to_lowercase();
+        domain = "gmail.com".to_string();
+        if local.is_empty() {
+            return Err(invalid());
+        }
+    }
+    Ok(format!("{}@{}", local, domain))
+}
+
+fn verification_ttl_secs() -> i64 {
+    env::var("EMAIL_VERIFICATION_TTL_SECS")
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_VERIFICATION_TTL_SECS)
+}
+
+/// Codes are only six digits, so the hash is keyed by the address to keep
+/// one precomputed table from covering every pending verification.
+fn hash_secret(email: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(email.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(secret.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Compares two hashes in constant time.
+pub fn hashes_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// A link to `path` in the web app carrying `token`, for emails. The base is
+/// `PUBLIC_URL`, falling back to `VITE_GATEWAY`; without either, emails carry
+/// the bare code or token instead.
+pub fn public_link(path: &str, token: &str) -> Option<String> {
+    let base = env::var("PUBLIC_URL")
+        .or_else(|_| env::var("VITE_GATEWAY"))
+        .ok()?;
+    let base = base.split(',').next()?.trim().trim_end_matches('/');
+    if base.is_empty() {
+        return None;
+    }
+    Some(format!("{}{}?token={}", base, path, token))
+}
+
+fn seconds_since(timestamp: &str, now: NaiveDateTime) -> i64 {
+    NaiveDateTime::parse_from_str(timestamp, SQLITE_DATETIME)
+        .map(|t| (now - t).num_seconds())
+        .unwrap_or(i64::MAX)
+}
+
+#[derive(sqlx::FromRow)]
+struct SendHistory {
+    last_sent_at: String,
+    send_count: i64,
+    window_started_at: String,
+}
+
+/// Seconds until another code may be sent to `email`, or 0 if one may be sent now.
+async fn resend_wait(state: &AppState, email: &str) -> Result<i64, AppError> {
+    let history: Option<SendHistory> = sqlx::query_as(
+        "SELECT last_sent_at, send_count, window_started_at FROM email_verifications WHERE email = ?",
+    )
+    .bind(email)
+    .fetch_optional(&state.db.email)
+    .await?;
+    let Some(history) = history else {
+        return Ok(0);
+    };
+
+    let now = Utc::now().naive_utc();
+    let interval_wait = RESEND_INTERVAL_SECS - seconds_since(&history.last_sent_at, now);
+    let window_age = seconds_since(&history.window_started_at, now);
+    let window_wait = if window_age < SEND_WINDOW_SECS && history.send_count >= MAX_SENDS_PER_WINDOW {
+        SEND_WINDOW_SECS - window_age
+    } else {
+        0
+    };
+    Ok(interval_wait.max(window_wait).max(0))
+}
+
+/// A code and link stored for an email, not yet sent.
+struct IssuedVerification {
+    code: String,
+    token: String,
+    expires_at: String,
+}
+
+/// Stores a fresh code and link for `email`, replacing any pending one of
+/// the same user. Returns `None`, storing nothing, if the pending
+/// verification belongs to another account.
+async fn store_verification(
+    conn: impl SqliteExecutor<'_>,
+    email: &str,
+    user_id: &str,
+) -> Result<Option<IssuedVerification>, AppError> {
+    let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    let expires_at: Option<String> = sqlx::query_scalar(
+        "INSERT INTO email_verifications (email, user_id, code_hash, token_hash, expires_at)
+        VALUES (?, ?, ?, ?, datetime('now', '+' || ? || ' seconds'))
+        ON CONFLICT (email) DO UPDATE SET
+            code_hash = excluded.code_hash,
+            token_hash = excluded.token_hash,
+            expires_at = excluded.expires_at,
+            attempts = 0,
+            last_sent_at = CURRENT_TIMESTAMP,
+            send_count = CASE WHEN window_started_at > datetime('now', '-' || ? || ' seconds')
+                THEN send_count + 1 ELSE 1 END,
+            window_started_at = CASE WHEN window_started_at > datetime('now', '-' || ? || ' seconds')
+                THEN window_started_at ELSE CURRENT_TIMESTAMP END
+        WHERE email_verifications.user_id = excluded.user_id
+        RETURNING expires_at",
+    )
+    .bind(email)
+    .bind(user_id)
+    .bind(hash_secret(email, &code))
+    .bind(hash_token(&token))
+    .bind(verification_ttl_secs())
+    .bind(SEND_WINDOW_SECS)
+    .bind(SEND_WINDOW_SECS)
+    .fetch_optional(conn)
+    .await?;
+    Ok(expires_at.map(|expires_at| IssuedVerification {
+        code,
+        token,
+        expires_at,
+    }))
+}
+
+/// Sends a stored code and link in the user's language.
+async fn send_verification(
+    state: &AppState,
+    email: &str,
+    user_id: &str,
+    issued: &IssuedVerification,
+    headers: &HeaderMap,
+) -> Result<(), AppError> {
+    let stored_locale = users::fetch_user(&state.db, user_id).await?.locale;
+    let locale = state
+        .mailer
+        .templates()
+        .choose_locale(stored_locale.as_deref(), Some(headers));
+    let mut context = tera::Context::new();
+    context.insert("email", email);
+    context.insert("code", &issued.code);
+    context.insert("expires_minutes", &(verification_ttl_secs() / 60));
+    context.insert("link", &public_link("/verify-email", &issued.token));
+    state
+        .mailer
+        .enqueue_template(email, EmailKind::Verification, &locale, context)
+        .await?;
+    Ok(())
+}
+
+/// The user's verified addresses, where security notices are sent.
+pub async fn verified_emails(state: &AppState, user_id: &str) -> sqlx::Result<Vec<String>> {
+    sqlx::query_scalar("SELECT email FROM email_users WHERE user_id = ? AND verified = 1")
+        .bind(user_id)
+        .fetch_all(&state.db.email)
+        .await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddEmail {
+    email: String,
+}
+
+/// `POST /v1/emails`
+///
+/// Binds an unverified email to the signed-in user and sends it a
+/// verification code and link. Calling it again for the same pending email
+/// resends, subject to the resend throttle. An unverified email bound to
+/// another account can be claimed once the code sent for that account has
+/// expired; a binding with no verification on record is never released.
+pub async fn add_email(
+    State(state): State<AppState>,
+    session: SessionUser,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<AddEmail>,
+) -> Result<Response, AppError> {
+    let email = normalize(&body.email).map_err(|e| AppError::Validation(vec![e]))?;
+    let taken = || AppError::Conflict {
+        code: "email.taken",
+        message: "This email is bound to another account.".to_string(),
+    };
+
+    let existing: Option<(String, bool)> =
+        sqlx::query_as("SELECT user_id, verified FROM email_users WHERE email = ?")
+            .bind(&email)
+            .fetch_optional(&state.db.email)
+            .await?;
+    match existing {
+        Some((owner, true)) if owner == session.user_id => {
+            return Err(AppError::Conflict {
+                code: "email.already_verified",
+                message: "This email is already verified on your account.".to_string(),
+            });
+        }
+        Some((owner, verified)) if owner != session.user_id => {
+            if verified {
+                return Err(taken());
+            }
+            // A stale claim by another account: never verified, and the code
+            // sent for it has expired. Both conditions are re-checked by the
+            // deletes, so a claim verified meanwhile is left alone.
+            let mut tx = state.db.email.begin().await?;
+            let released = sqlx::query(
+                "DELETE FROM email_users
+                WHERE email = ? AND user_id = ? AND verified = 0
+                    AND EXISTS(SELECT 1 FROM email_verifications
+                        WHERE email = email_users.email AND user_id = email_users.user_id
+                            AND expires_at <= datetime('now'))",
+            )
+            .bind(&email)
+            .bind(&owner)
+            .execute(&mut *tx)
+            .await?;
+            if released.rows_affected() == 0 {
+                return Err(taken());
+            }
+            sqlx::query("DELETE FROM email_verifications WHERE email = ?")
+                .bind(&email)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        }
+        _ => {}
+    }
+
+    let wait = resend_wait(&state, &email).await?;
+    if wait > 0 {
+        return Err(AppError::Throttled {
+            code: "email.resend_throttled",
+            message: "A code was sent recently. Try again later.".to_string(),
+            retry_after: wait as u64,
+        });
+    }
+
+    // The binding and its verification are written together, so an account
+    // racing for the same address either owns both or neither.
+    let mut tx = state.db.email.begin_with("BEGIN IMMEDIATE").await?;
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM email_users WHERE user_id = ? AND email != ?",
+    )
+    .bind(&session.user_id)
+    .bind(&email)
+    .fetch_one(&mut *tx)
+    .await?;
+    if count >= MAX_EMAILS_PER_USER {
+        return Err(AppError::Conflict {
+            code: "email.limit_reached",
+            message: format!("An account can have at most {} emails.", MAX_EMAILS_PER_USER),
+        });
+    }
+
+    sqlx::query("INSERT OR IGNORE INTO email_users (email, user_id) VALUES (?, ?)")
+        .bind(&email)
+        .bind(&session.user_id)
+        .execute(&mut *tx)
+        .await?;
+    let owner: Option<(String, bool)> =
+        sqlx::query_as("SELECT user_id, verified FROM email_users WHERE email = ?")
+            .bind(&email)
+            .fetch_optional(&mut *tx)
+            .await?;
+    if !owner.is_some_and(|(owner, verified)| owner == session.user_id && !verified) {
+        return Err(taken());
+    }
+    // A verification left by an earlier owner of the binding is stale.
+    sqlx::query("DELETE FROM email_verifications WHERE email = ? AND user_id != ?")
+        .bind(&email)
+        .bind(&session.user_id)
+        .execute(&mut *tx)
+        .await?;
+    let issued = store_verification(&mut *tx, &email, &session.user_id)
+        .await?
+        .ok_or_else(taken)?;
+    tx.commit().await?;
+    send_verification(&state, &email, &session.user_id, &issued, &headers).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(&session.user_id, "email.add", Outcome::Success)
+            .target_user(&session.user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    Ok(response::success(Some(json!({
+        "email": email,
+        "verified": false,
+        "expires_at": issued.expires_at,
+    }))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResendVerification {
+    email: String,
+}
+
+/// `POST /v1/emails/resend`
+///
+/// Sends a new code and link to a pending email. Works without a session so
+/// that users who cannot sign in before verifying can still finish. The
+/// response is the same whether or not anything was sent, so it cannot be
+/// used to learn which addresses are bound.
+pub async fn resend_verification(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<ResendVerification>,
+) -> Result<Response, AppError> {
+    let email = normalize(&body.email).map_err(|e| AppError::Validation(vec![e]))?;
+
+    let owner: Option<String> =
+        sqlx::query_scalar("SELECT user_id FROM email_users WHERE email = ? AND verified = 0")
+            .bind(&email)
+            .fetch_optional(&state.db.email)
+            .await?;
+    if let Some(user_id) = owner
+        && resend_wait(&state, &email).await? == 0
+        && let Some(issued) = store_verification(&state.db.email, &email, &user_id).await?
+    {
+        send_verification(&state, &email, &user_id, &issued, &headers).await?;
+    }
+
+    Ok(response::success(None))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VerifyEmail {
+    /// Token from the emailed link.
+    token: Option<String>,
+    /// Address and code, as an alternative to the token.
+    email: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingVerification {
+    email: String,
+    user_id: String,
+    expired: bool,
+}
+
+const PENDING_COLUMNS: &str = "email, user_id, expires_at <= datetime('now') AS expired";
+
+fn verification_not_found() -> AppError {
+    AppError::NotFound {
+        code: "email.verification_not_found",
+        message: "No pending verification matches.".to_string(),
+    }
+}
+
+/// `POST /v1/emails/verify`
+///
+/// Confirms an email with either the link token or the address and code.
+/// A code is voided after too many wrong attempts and must be resent.
+pub async fn verify_email(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<VerifyEmail>,
+) -> Result<Response, AppError> {
+    let pending: Option<PendingVerification> = match (&body.token, &body.email, &body.code) {
+        (Some(token), _, _) => sqlx::query_as(&format!(
+            "SELECT {} FROM email_verifications WHERE token_hash = ?",
+            PENDING_COLUMNS
+        ))
+        .bind(hash_token(token.trim()))
+        .fetch_optional(&state.db.email)
+        .await?,
+        (None, Some(email), Some(_)) => {
+            let email = normalize(email).map_err(|e| AppError::Validation(vec![e]))?;
+            sqlx::query_as(&format!(
+                "SELECT {} FROM email_verifications WHERE email = ?",
+                PENDING_COLUMNS
+            ))
+            .bind(&email)
+            .fetch_optional(&state.db.email)
+            .await?
+        }
+        _ => {
+            return Err(AppError::Validation(vec![FieldError::new(
+                "token",
+                "required",
+                "Provide either token, or email and code.",
+            )]));
+        }
+    };
+    let pending = pending.ok_or_else(verification_not_found)?;
+
+    if pending.expired {
+        return Err(AppError::BadRequest {
+            code: "email.code_expired",
+            message: "The code has expired. Request a new one.".to_string(),
+        });
+    }
+    if body.token.is_none() {
+        // The attempt is claimed before the code is compared, so parallel
+        // guesses cannot get past the limit.
+        let claimed: Option<(i64, String)> = sqlx::query_as(
+            "UPDATE email_verifications SET attempts = attempts + 1
+            WHERE email = ? AND attempts < ?
+            RETURNING attempts, code_hash",
+        )
+        .bind(&pending.email)
+        .bind(MAX_CODE_ATTEMPTS)
+        .fetch_optional(&state.db.email)
+        .await?;
+        let Some((attempts, code_hash)) = claimed else {
+            return Err(AppError::BadRequest {
+                code: "email.attempts_exceeded",
+                message: "Too many wrong codes. Request a new one.".to_string(),
+            });
+        };
+        let code = body.code.as_deref().unwrap_or_default().trim();
+        if !hashes_match(&hash_secret(&pending.email, code), &code_hash) {
+            state.audit.spawn_record(
+                AuditEvent::new("anonymous", "email.verify", Outcome::Failure)
+                    .target_user(&pending.user_id)
+                    .ip(client_ip.map(|ip| ip.0 .0)),
+            );
+            return Err(AppError::BadRequest {
+                code: "email.code_invalid",
+                message: format!(
+                    "The code is incorrect. {} attempt(s) left.",
+                    MAX_CODE_ATTEMPTS - attempts
+                ),
+            });
+        }
+    }
+
+    let mut tx = state.db.email.begin().await?;
+    let updated = sqlx::query(
+        "UPDATE email_users SET verified = 1, verified_at = CURRENT_TIMESTAMP
+        WHERE email = ? AND user_id = ?",
+    )
+    .bind(&pending.email)
+    .bind(&pending.user_id)
+    .execute(&mut *tx)
+    .await?;
+    // The address was removed from, or never bound to, the account the code
+    // was issued for.
+    if updated.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Err(AppError::BadRequest {
+            code: "email.verification_invalid",
+            message: "This address is no longer pending on the account the code was sent for."
+                .to_string(),
+        });
+    }
+    sqlx::query("DELETE FROM email_verifications WHERE email = ? AND user_id = ?")
+        .bind(&pending.email)
+        .bind(&pending.user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    account::activate_pending(&state.db, &pending.user_id).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(&pending.user_id, "email.verify", Outcome::Success)
+            .target_user(&pending.user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    Ok(response::success(Some(json!({
+        "email": pending.email,
+        "verified": true,
+    }))))
+}
@@ -0,0 +1,487 @@
+// src/email_login.rs
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    email,
+    error::{AppError, FieldError},
+    extract::AppJson,
+    logging::{log, LogLevel},
+    rate_limiting::ClientIp,
+    response, session,
+    templates::EmailKind,
+    users::{self, UserRow},
+    AppState,
+};
+use axum::{
+    extract::{Extension, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::{env, net::IpAddr};
+
+/// Cookie binding a pending email sign-in to the browser that requested it,
+/// so a forwarded or intercepted link is useless elsewhere.
+const NONCE_COOKIE: &str = "stardust_login_nonce";
+const NONCE_COOKIE_PATH: &str = "/v1/sessions/email";
+
+/// Seconds a link or code stays valid, unless `EMAIL_LOGIN_TTL_SECS` is set.
+const DEFAULT_TTL_SECS: i64 = 600;
+/// Requests per address per window, unless `EMAIL_LOGIN_MAX_PER_EMAIL` is set.
+const DEFAULT_MAX_PER_EMAIL: i64 = 5;
+/// Requests per IP per window, unless `EMAIL_LOGIN_MAX_PER_IP` is set.
+const DEFAULT_MAX_PER_IP: i64 = 20;
+const WINDOW_SECS: i64 = 3600;
+/// Wrong codes accepted before the pending sign-in is voided.
+const MAX_CODE_ATTEMPTS: i64 = 5;
+
+/// Spent and expired sign-ins are kept this long for the audit trail.
+const RETENTION_SECS: i64 = 24 * 3600;
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|val| *val > 0)
+        .unwrap_or(default)
+}
+
+fn nonce_cookie(nonce: &str, max_age: i64) -> HeaderValue {
+    let cookie = format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        NONCE_COOKIE, nonce, NONCE_COOKIE_PATH, max_age
+    );
+    HeaderValue::from_str(&cookie).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// Whether a cookie value has the shape of a nonce we issued: 32 random
+/// bytes, base64url-encoded.
+fn is_nonce(value: &str) -> bool {
+    URL_SAFE_NO_PAD.decode(value).is_ok_and(|bytes| bytes.len() == 32)
+}
+
+/// Codes are only six digits, so the hash is keyed by the browser nonce,
+/// which is never stored, to keep a leaked table from revealing them.
+fn hash_code(nonce: &str, code: &str) -> String {
+    email::hash_token(&format!("{}\n{}", nonce, code))
+}
+
+/// Seconds until another request matching `column = value` is allowed, or 0.
+async fn wait(
+    state: &AppState,
+    column: &str,
+    value: Option<&str>,
+    max: i64,
+) -> Result<i64, AppError> {
+    // Ages of the most recent `max` requests in the window, newest first.
+    let ages: Vec<i64> = sqlx::query_scalar(&format!(
+        "SELECT CAST(strftime('%s', 'now') AS INTEGER) - CAST(strftime('%s', created_at) AS INTEGER)
+        FROM email_login_attempts
+        WHERE {} IS ? AND created_at > datetime('now', '-' || ? || ' seconds')
+        ORDER BY created_at DESC, id DESC
+        LIMIT ?",
+        column
+    ))
+    .bind(value)
+    .bind(WINDOW_SECS)
+    .bind(max)
+    .fetch_all(&state.db.email)
+    .await?;
+    Ok(match ages.last() {
+        Some(age) if ages.len() as i64 >= max => (WINDOW_SECS - age).max(1),
+        _ => 0,
+    })
+}
+
+/// Drops requests that no longer count and sign-ins past their retention.
+async fn prune(state: &AppState) -> Result<(), AppError> {
+    sqlx::query(
+        "DELETE FROM email_login_attempts WHERE created_at <= datetime('now', '-' || ? || ' seconds')",
+    )
+    .bind(WINDOW_SECS)
+    .execute(&state.db.email)
+    .await?;
+    sqlx::query("DELETE FROM email_logins WHERE expires_at <= datetime('now', '-' || ? || ' seconds')")
+        .bind(RETENTION_SECS)
+        .execute(&state.db.email)
+        .await?;
+    Ok(())
+}
+
+/// Issues a link and code for `user`, voiding any earlier ones requested
+/// from the same browser, and emails them to `email`. Pending sign-ins of
+/// other browsers are left alone, so requesting one for somebody else's
+/// address cannot cancel theirs.
+async fn issue_login(
+    state: &AppState,
+    user: &UserRow,
+    email: &str,
+    nonce: &str,
+    ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> anyhow::Result<()> {
+    let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let ttl = env_i64("EMAIL_LOGIN_TTL_SECS", DEFAULT_TTL_SECS);
+
+    let mut tx = state.db.email.begin().await?;
+    sqlx::query(
+        "UPDATE email_logins SET consumed_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND nonce_hash = ? AND consumed_at IS NULL",
+    )
+    .bind(&user.user_id)
+    .bind(email::hash_token(nonce))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO email_logins (user_id, email, token_hash, code_hash, nonce_hash, expires_at, ip)
+        VALUES (?, ?, ?, ?, ?, datetime('now', '+' || ? || ' seconds'), ?)",
+    )
+    .bind(&user.user_id)
+    .bind(email)
+    .bind(email::hash_token(&token))
+    .bind(hash_code(nonce, &code))
+    .bind(email::hash_token(nonce))
+    .bind(ttl)
+    .bind(ip.map(|ip| ip.to_string()))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let locale = state
+        .mailer
+        .templates()
+        .choose_locale(user.locale.as_deref(), Some(headers));
+    let mut context = tera::Context::new();
+    context.insert("email", email);
+    context.insert("code", &code);
+    context.insert("link", &email::public_link("/login/email", &token));
+    context.insert("expires_minutes", &(ttl / 60));
+    state
+        .mailer
+        .enqueue_template(email, EmailKind::LoginLink, &locale, context)
+        .await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new("anonymous", "auth.email_login_request", Outcome::Success)
+            .target_user(&user.user_id)
+            .ip(ip),
+    );
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RequestLogin {
+    email: String,
+}
+
+/// `POST /v1/sessions/email`
+///
+/// Emails a sign-in link and code to a verified address, and sets a cookie
+/// that they only work alongside. The response is the same whether or not
+/// the address belongs to an account, and the email is prepared in the
+/// background so timing does not tell either.
+pub async fn request_login(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<RequestLogin>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let email = email::normalize(&body.email).map_err(|e| AppError::Validation(vec![e]))?;
+
+    let email_wait = wait(
+        &state,
+        "email",
+        Some(&email),
+        env_i64("EMAIL_LOGIN_MAX_PER_EMAIL", DEFAULT_MAX_PER_EMAIL),
+    )
+    .await?;
+    let ip_wait = wait(
+        &state,
+        "ip",
+        ip.map(|ip| ip.to_string()).as_deref(),
+        env_i64("EMAIL_LOGIN_MAX_PER_IP", DEFAULT_MAX_PER_IP),
+    )
+    .await?;
+    if email_wait.max(ip_wait) > 0 {
+        return Err(AppError::Throttled {
+            code: "email_login.throttled",
+            message: "Too many sign-in emails requested. Try again later.".to_string(),
+            retry_after: email_wait.max(ip_wait) as u64,
+        });
+    }
+    prune(&state).await?;
+    sqlx::query("INSERT INTO email_login_attempts (email, ip) VALUES (?, ?)")
+        .bind(&email)
+        .bind(ip.map(|ip| ip.to_string()))
+        .execute(&state.db.email)
+        .await?;
+
+    // A browser asking again keeps its nonce, so its earlier link and code
+    // are replaced rather than left pending.
+    let nonce = session::cookie_value(&headers, NONCE_COOKIE)
+        .filter(|nonce| is_nonce(nonce))
+        .unwrap_or_else(|| {
+            let mut bytes = [0u8; 32];
+            rand::rng().fill_bytes(&mut bytes);
+            URL_SAFE_NO_PAD.encode(bytes)
+        });
+
+    let owner: Option<String> =
+        sqlx::query_scalar("SELECT user_id FROM email_users WHERE email = ? AND verified = 1")
+            .bind(&email)
+            .fetch_optional(&state.db.email)
+            .await?;
+    if let Some(user_id) = owner {
+        let nonce = nonce.clone();
+        tokio::spawn(async move {
+            let user = match users::fetch_user(&state.db, &user_id).await {
+                Ok(user) => user,
+                Err(AppError::NotFound { .. }) => return,
+                Err(e) => {
+                    log(LogLevel::Error, &format!("Email sign-in lookup failed: {}", e));
+                    return;
+                }
+            };
+            if let Err(e) = issue_login(&state, &user, &email, &nonce, ip, &headers).await {
+                log(LogLevel::Error, &format!("Failed to issue email sign-in: {:#}", e));
+            }
+        });
+    }
+
+    let mut response = response::success(None);
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        nonce_cookie(&nonce, env_i64("EMAIL_LOGIN_TTL_SECS", DEFAULT_TTL_SECS)),
+    );
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct VerifyLogin {
+    /// Token from the emailed link.
+    token: Option<String>,
+    /// The emailed code, as an alternative to the token.
+    code: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingLogin {
+    id: i64,
+    user_id: String,
+    email: String,
+    code_hash: String,
+    nonce_hash: String,
+}
+
+const PENDING_COLUMNS: &str = "id, user_id, email, code_hash, nonce_hash";
+
+fn login_invalid() -> AppError {
+    AppError::BadRequest {
+        code: "email_login.invalid",
+        message: "The sign-in link or code is invalid or has expired. Request a new one.".to_string(),
+    }
+}
+
+/// The request lacks the nonce cookie of the sign-in it answers, e.g. a
+/// link forwarded to another device.
+fn browser_mismatch() -> AppError {
+    AppError::BadRequest {
+        code: "email_login.browser_mismatch",
+        message: "Open the link or enter the code in the browser where you requested it."
+            .to_string(),
+    }
+}
+
+/// Claims one of the code attempts of a pending sign-in before the code is
+/// compared, so concurrent guesses cannot all be checked against the same
+/// remaining count. Returns the attempts used, or `None` once they are spent.
+async fn claim_attempt(db: &SqlitePool, id: i64) -> Result<Option<i64>, AppError> {
+    Ok(sqlx::query_scalar(
+        "UPDATE email_logins SET attempts = attempts + 1
+        WHERE id = ? AND attempts < ?
+        RETURNING attempts",
+    )
+    .bind(id)
+    .bind(MAX_CODE_ATTEMPTS)
+    .fetch_optional(db)
+    .await?)
+}
+
+/// `POST /v1/sessions/email/verify`
+///
+/// Exchanges the emailed link token or code for a session, in the browser
+/// that requested it. Each works once, and a code is voided after too many
+/// wrong attempts. Wrong codes also count towards the account lock. Users with a second factor get an MFA challenge instead
+/// of a session, as with a password.
+pub async fn verify_login(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<VerifyLogin>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let nonce = session::cookie_value(&headers, NONCE_COOKIE).ok_or_else(browser_mismatch)?;
+    let nonce_hash = email::hash_token(&nonce);
+
+    let pending: Option<PendingLogin> = match (&body.token, &body.code) {
+        (Some(token), _) => sqlx::query_as(&format!(
+            "SELECT {} FROM email_logins
+            WHERE token_hash = ? AND consumed_at IS NULL AND expires_at > datetime('now')",
+            PENDING_COLUMNS
+        ))
+        .bind(email::hash_token(token.trim()))
+        .fetch_optional(&state.db.email)
+        .await?,
+        (None, Some(_)) => sqlx::query_as(&format!(
+            "SELECT {} FROM email_logins
+            WHERE nonce_hash = ? AND consumed_at IS NULL AND expires_at > datetime('now')
+            ORDER BY id DESC LIMIT 1",
+            PENDING_COLUMNS
+        ))
+        .bind(&nonce_hash)
+        .fetch_optional(&state.db.email)
+        .await?,
+        (None, None) => {
+            return Err(AppError::Validation(vec![FieldError::new(
+                "token",
+                "required",
+                "Provide either token or code.",
+            )]));
+        }
+    };
+    let Some(pending) = pending else {
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "auth.login", Outcome::Failure).ip(ip),
+        );
+        return Err(login_invalid());
+    };
+
+    if !email::hashes_match(&pending.nonce_hash, &nonce_hash) {
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "auth.login", Outcome::Failure)
+                .target_user(&pending.user_id)
+                .ip(ip),
+        );
+        return Err(browser_mismatch());
+    }
+    let user = match users::fetch_user(&state.db, &pending.user_id).await {
+        Ok(user) => user,
+        Err(AppError::NotFound { .. }) => return Err(login_invalid()),
+        Err(e) => return Err(e),
+    };
+    if body.token.is_none() {
+        // Guessing stops while the account is locked, like a password.
+        session::ensure_can_sign_in(&state, &user, ip)?;
+        let Some(attempts) = claim_attempt(&state.db.email, pending.id).await? else {
+            return Err(login_invalid());
+        };
+        let code = body.code.as_deref().unwrap_or_default().trim();
+        if !email::hashes_match(&hash_code(&nonce, code), &pending.code_hash) {
+            // Counts towards the account lock, so requesting code after code
+            // does not allow unlimited guesses.
+            session::count_failed_login(&state, &user, ip).await?;
+            state.audit.spawn_record(
+                AuditEvent::new("anonymous", "auth.login", Outcome::Failure)
+                    .target_user(&pending.user_id)
+                    .ip(ip),
+            );
+            return Err(AppError::BadRequest {
+                code: "email_login.code_invalid",
+                message: format!(
+                    "The code is incorrect. {} attempt(s) left.",
+                    MAX_CODE_ATTEMPTS - attempts
+                ),
+            });
+        }
+    }
+
+    // Spends the sign-in, unless a concurrent request got there first.
+    let spent = sqlx::query(
+        "UPDATE email_logins SET consumed_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND consumed_at IS NULL AND expires_at > datetime('now')",
+    )
+    .bind(pending.id)
+    .execute(&state.db.email)
+    .await?;
+    if spent.rows_affected() == 0 {
+        return Err(login_invalid());
+    }
+
+    // The address may have been removed from the account since it was sent.
+    let still_bound: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM email_users WHERE email = ? AND user_id = ? AND verified = 1)",
+    )
+    .bind(&pending.email)
+    .bind(&pending.user_id)
+    .fetch_one(&state.db.email)
+    .await?;
+    if !still_bound {
+        return Err(login_invalid());
+    }
+
+    let mut response = session::continue_sign_in(&state, &user, ip, &headers).await?;
+    response
+        .headers_mut()
+        .append(header::SET_COOKIE, nonce_cookie("", 0));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+
+    fn nonce() -> String {
+        let mut bytes = [0u8; 32];
+        rand::rng().fill_bytes(&mut bytes);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn code_hashes_are_bound_to_the_nonce() {
+        let (ours, theirs) = (nonce(), nonce());
+        assert_eq!(hash_code(&ours, "123456"), hash_code(&ours, "123456"));
+        assert_ne!(hash_code(&ours, "123456"), hash_code(&theirs, "123456"));
+        assert_ne!(hash_code(&ours, "123456"), hash_code(&ours, "123457"));
+    }
+
+    #[test]
+    fn only_issued_nonces_are_reused() {
+        assert!(is_nonce(&nonce()));
+        assert!(!is_nonce(""));
+        assert!(!is_nonce("short"));
+        assert!(!is_nonce(&format!("{}!", &nonce()[1..])));
+    }
+
+    #[tokio::test]
+    async fn attempts_run_out() {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        let dir = env::temp_dir().join(format!("stardust-email-login-{}", hex::encode(unique)));
+        let db = sqlite::initialize_email_db(&dir).await.unwrap();
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO email_logins (user_id, email, token_hash, code_hash, nonce_hash, expires_at)
+            VALUES ('u1', 'a@example.com', 'token', 'code', 'nonce', datetime('now', '+600 seconds'))
+            RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+
+        for expected in 1..=MAX_CODE_ATTEMPTS {
+            assert_eq!(claim_attempt(&db, id).await.unwrap(), Some(expected));
+        }
+        assert_eq!(claim_attempt(&db, id).await.unwrap(), None);
+        assert_eq!(claim_attempt(&db, id + 1).await.unwrap(), None);
+
+        db.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
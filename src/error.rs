@@ -0,0 +1,193 @@
+// src/error.rs
+
+use crate::{
+    logging::{log, LogLevel},
+    response,
+};
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+
+/// A single field-level validation failure, rendered under `details`.
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// The application-wide error type.
+///
+/// Every variant maps to an HTTP status and a stable, machine-readable code
+/// such as `auth.token_invalid`. Clients should branch on the code, never on
+/// the human-readable message.
+#[derive(Debug)]
+pub enum AppError {
+    AuthHeaderMissing,
+    AuthHeaderMalformed,
+    TokenInvalid,
+    /// Wrong identifier or password. Deliberately does not say which.
+    InvalidCredentials,
+    /// The user session token is missing, expired or revoked.
+    SessionInvalid,
+    /// A TOTP code, recovery code or passkey was not accepted as a second factor.
+    SecondFactorInvalid,
+    /// A passkey sign-in was not accepted. Deliberately does not say why.
+    PasskeyInvalid,
+    RateLimited,
+    /// A per-resource limit such as a resend cooldown, as opposed to the
+    /// per-IP request limit. Sets `Retry-After`.
+    Throttled {
+        code: &'static str,
+        message: String,
+        retry_after: u64,
+    },
+    Validation(Vec<FieldError>),
+    BadRequest { code: &'static str, message: String },
+    Forbidden { code: &'static str, message: String },
+    NotFound { code: &'static str, message: String },
+    Conflict { code: &'static str, message: String },
+    /// The user is identified but their account status forbids authenticating.
+    AccountInactive {
+        code: &'static str,
+        message: String,
+        details: Option<serde_json::Value>,
+    },
+    Unavailable {
+        code: &'static str,
+        message: String,
+        details: Option<serde_json::Value>,
+    },
+    /// Unexpected failures. The cause is logged but never sent to the client.
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::AuthHeaderMissing
+            | AppError::AuthHeaderMalformed
+            | AppError::TokenInvalid
+            | AppError::InvalidCredentials
+            | AppError::SessionInvalid
+            | AppError::SecondFactorInvalid
+            | AppError::PasskeyInvalid => StatusCode::UNAUTHORIZED,
+            AppError::RateLimited | AppError::Throttled { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            AppError::Forbidden { .. } | AppError::AccountInactive { .. } => StatusCode::FORBIDDEN,
+            AppError::NotFound { .. } => StatusCode::NOT_FOUND,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::AuthHeaderMissing => "auth.header_missing",
+            AppError::AuthHeaderMalformed => "auth.header_malformed",
+            AppError::TokenInvalid => "auth.token_invalid",
+            AppError::InvalidCredentials => "auth.invalid_credentials",
+            AppError::SessionInvalid => "auth.session_invalid",
+            AppError::SecondFactorInvalid => "auth.second_factor_invalid",
+            AppError::PasskeyInvalid => "auth.passkey_invalid",
+            AppError::RateLimited => "rate_limit.exceeded",
+            AppError::Validation(_) => "validation.failed",
+            AppError::BadRequest { code, .. }
+            | AppError::Forbidden { code, .. }
+            | AppError::NotFound { code, .. }
+            | AppError::Conflict { code, .. }
+            | AppError::AccountInactive { code, .. }
+            | AppError::Throttled { code, .. }
+            | AppError::Unavailable { code, .. } => code,
+            AppError::Internal(_) => "internal.error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            AppError::AuthHeaderMissing => "Authorization header is missing.".to_string(),
+            AppError::AuthHeaderMalformed => "Invalid authorization header format.".to_string(),
+            AppError::TokenInvalid => "Invalid authentication token.".to_string(),
+            AppError::InvalidCredentials => "Invalid identifier or password.".to_string(),
+            AppError::SessionInvalid => "Session is missing, expired or revoked.".to_string(),
+            AppError::SecondFactorInvalid => {
+                "The verification code or passkey was not accepted.".to_string()
+            }
+            AppError::PasskeyInvalid => "The passkey was not accepted.".to_string(),
+            AppError::RateLimited => "Too many requests".to_string(),
+            AppError::Validation(_) => "Request validation failed.".to_string(),
+            AppError::BadRequest { message, .. }
+            | AppError::Forbidden { message, .. }
+            | AppError::NotFound { message, .. }
+            | AppError::Conflict { message, .. }
+            | AppError::AccountInactive { message, .. }
+            | AppError::Throttled { message, .. }
+            | AppError::Unavailable { message, .. } => message.clone(),
+            AppError::Internal(_) => "Internal server error.".to_string(),
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            AppError::Validation(fields) => serde_json::to_value(fields).ok(),
+            AppError::Throttled { retry_after, .. } => Some(json!({ "retry_after": retry_after })),
+            AppError::AccountInactive { details, .. } | AppError::Unavailable { details, .. } => {
+                details.clone()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Internal(e) => write!(f, "{}: {:#}", self.code(), e),
+            _ => write!(f, "{}: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::Internal(e) = &self {
+            log(LogLevel::Error, &format!("Internal error: {:#}", e));
+        }
+        let mut response =
+            response::error(self.status(), self.code(), self.message(), self.details());
+        if let AppError::Throttled { retry_after, .. } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        AppError::Internal(e)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        AppError::Internal(e.into())
+    }
+}
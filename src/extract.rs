@@ -0,0 +1,38 @@
+// src/extract.rs
+
+use crate::error::AppError;
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        FromRequest, FromRequestParts, Query,
+    },
+    Json,
+};
+
+/// `Json` extractor whose rejections are rendered as `AppError`.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(AppError))]
+pub struct AppJson<T>(pub T);
+
+/// `Query` extractor whose rejections are rendered as `AppError`.
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(AppError))]
+pub struct AppQuery<T>(pub T);
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError::BadRequest {
+            code: "request.invalid_json",
+            message: rejection.body_text(),
+        }
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::BadRequest {
+            code: "request.invalid_query",
+            message: rejection.body_text(),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+// src/handle.rs
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    auth::Caller,
+    error::{AppError, FieldError},
+    extract::AppJson,
+    logging::{log, LogLevel},
+    rate_limiting::ClientIp,
+    rbac::{self, Permission},
+    response,
+    users::fetch_user,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Path, State},
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::SqliteExecutor;
+use std::{collections::HashSet, env, fs};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, MixedScript};
+
+/// Reserved handles, one per line. Created with `DEFAULT_RESERVED` if missing,
+/// unless `HANDLE_RESERVED_FILE` points elsewhere.
+const RESERVED_FILE: &str = "/opt/stardust/etc/reserved_handles";
+
+const DEFAULT_RESERVED: &str = "\
+# Handles nobody can claim, one per line. Matching ignores case and
+# look-alike characters, so `Admin` and `аdmin` (Cyrillic a) are covered too.
+about
+account
+admin
+administrator
+api
+assets
+help
+login
+logout
+me
+moderator
+null
+official
+owner
+register
+root
+security
+settings
+signup
+staff
+stardust
+static
+support
+system
+undefined
+www
+";
+
+const DEFAULT_MIN_LEN: usize = 3;
+const DEFAULT_MAX_LEN: usize = 30;
+/// Symbols allowed between letters and digits, unless `HANDLE_SYMBOLS` is set.
+const DEFAULT_SYMBOLS: &str = "_.-";
+
+/// Seconds a released handle stays blocked for everyone but its previous
+/// owner, unless `HANDLE_RELEASE_COOLDOWN_SECS` is set.
+const DEFAULT_RELEASE_COOLDOWN_SECS: i64 = 30 * 24 * 3600;
+/// Handle changes a user may make per window, unless `HANDLE_MAX_CHANGES`
+/// and `HANDLE_CHANGE_WINDOW_SECS` are set. A limit of 0 disables it.
+const DEFAULT_MAX_CHANGES: i64 = 3;
+const DEFAULT_CHANGE_WINDOW_SECS: i64 = 30 * 24 * 3600;
+
+/// Rules a handle must follow, read from the environment at startup.
+pub struct HandlePolicy {
+    min_len: usize,
+    max_len: usize,
+    symbols: Vec<char>,
+    /// Allows letters and digits outside ASCII, from a single script.
+    allow_unicode: bool,
+    /// Canonical keys of reserved handles.
+    reserved: HashSet<String>,
+    release_cooldown_secs: i64,
+    max_changes: i64,
+    change_window_secs: i64,
+}
+
+impl HandlePolicy {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let env_usize = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .unwrap_or(default)
+        };
+        let env_i64 = |name: &str, default: i64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v >= 0)
+                .unwrap_or(default)
+        };
+
+        let path = env::var("HANDLE_RESERVED_FILE").unwrap_or_else(|_| RESERVED_FILE.to_string());
+        if !std::path::Path::new(&path).exists() {
+            fs::write(&path, DEFAULT_RESERVED)?;
+            log(LogLevel::Info, &format!("Created reserved handles list: {}", path));
+        }
+        let reserved: HashSet<String> = fs::read_to_string(&path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(canonical)
+            .collect();
+        log(
+            LogLevel::Debug,
+            &format!("Loaded {} reserved handles from {}", reserved.len(), path),
+        );
+
+        Ok(Self {
+            min_len: env_usize("HANDLE_MIN_LEN", DEFAULT_MIN_LEN),
+            max_len: env_usize("HANDLE_MAX_LEN", DEFAULT_MAX_LEN),
+            symbols: env::var("HANDLE_SYMBOLS")
+                .unwrap_or_else(|_| DEFAULT_SYMBOLS.to_string())
+                .chars()
+                .filter(|c| !c.is_alphanumeric() && !c.is_whitespace())
+                .collect(),
+            allow_unicode: env::var("HANDLE_ALLOW_UNICODE").is_ok_and(|v| v == "true"),
+            reserved,
+            release_cooldown_secs: env_i64("HANDLE_RELEASE_COOLDOWN_SECS", DEFAULT_RELEASE_COOLDOWN_SECS),
+            max_changes: env_i64("HANDLE_MAX_CHANGES", DEFAULT_MAX_CHANGES),
+            change_window_secs: env_i64("HANDLE_CHANGE_WINDOW_SECS", DEFAULT_CHANGE_WINDOW_SECS),
+        })
+    }
+
+    fn is_letter_or_digit(&self, c: char) -> bool {
+        if self.allow_unicode {
+            c.is_alphanumeric() && c.identifier_allowed()
+        } else {
+            c.is_ascii_alphanumeric()
+        }
+    }
+
+    /// Checks the format of `raw` and returns it in NFC, as displayed. Being
+    /// reserved is not a format error; see `is_reserved`.
+    pub fn validate(&self, raw: &str) -> Result<String, FieldError> {
+        let handle: String = raw.trim().nfc().collect();
+        let error = |code, message: String| Err(FieldError::new("handle", code, message));
+
+        let len = handle.chars().count();
+        if len < self.min_len {
+            return error("too_short", format!("Must be at least {} characters.", self.min_len));
+        }
+        if len > self.max_len {
+            return error("too_long", format!("Must be at most {} characters.", self.max_len));
+        }
+        if !handle
+            .chars()
+            .all(|c| self.is_letter_or_digit(c) || self.symbols.contains(&c))
+        {
+            let symbols: String = self.symbols.iter().collect();
+            return error(
+                "invalid_characters",
+                if symbols.is_empty() {
+                    "Only letters and digits are allowed.".to_string()
+                } else {
+                    format!("Only letters, digits and {} are allowed.", symbols)
+                },
+            );
+        }
+        let chars: Vec<char> = handle.chars().collect();
+        if self.symbols.contains(&chars[0]) || self.symbols.contains(&chars[len - 1]) {
+            return error(
+                "symbol_at_edge",
+                "Must start and end with a letter or digit.".to_string(),
+            );
+        }
+        if chars
+            .windows(2)
+            .any(|pair| self.symbols.contains(&pair[0]) && self.symbols.contains(&pair[1]))
+        {
+            return error(
+                "consecutive_symbols",
+                "Symbols cannot be next to each other.".to_string(),
+            );
+        }
+        if !handle.as_str().is_single_script() {
+            return error(
+                "mixed_script",
+                "Cannot mix characters from different scripts.".to_string(),
+            );
+        }
+        Ok(handle)
+    }
+
+    pub fn is_reserved(&self, handle: &str) -> bool {
+        self.reserved.contains(&canonical(handle))
+    }
+}
+
+/// The key handles are compared by: compatibility-normalized, lowercased
+/// and reduced to its UTS #39 confusable skeleton, so that handles differing
+/// only in case or by look-alike characters collide.
+pub fn canonical(handle: &str) -> String {
+    let folded: String = handle.nfkc().collect::<String>().to_lowercase();
+    unicode_security::skeleton(&folded).collect()
+}
+
+/// The user a canonical handle key belongs to, if any.
+async fn key_owner(conn: impl SqliteExecutor<'_>, key: &str) -> Result<Option<String>, AppError> {
+    Ok(
+        sqlx::query_scalar("SELECT user_id FROM handle_users WHERE handle_key = ?")
+            .bind(key)
+            .fetch_optional(conn)
+            .await?,
+    )
+}
+
+/// Whether a handle was released by another user within the cooldown.
+async fn cooling_down(
+    conn: impl SqliteExecutor<'_>,
+    policy: &HandlePolicy,
+    key: &str,
+    user_id: Option<&str>,
+) -> Result<bool, AppError> {
+    Ok(sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM handle_history
+        WHERE handle_key = ? AND user_id IS NOT ?
+            AND released_at > datetime('now', '-' || ? || ' seconds'))",
+    )
+    .bind(key)
+    .bind(user_id)
+    .bind(policy.release_cooldown_secs)
+    .fetch_one(conn)
+    .await?)
+}
+
+/// Seconds until the user may change handle again, or 0.
+async fn change_wait(
+    conn: impl SqliteExecutor<'_>,
+    policy: &HandlePolicy,
+    user_id: &str,
+) -> Result<i64, AppError> {
+    if policy.max_changes == 0 {
+        return Ok(0);
+    }
+    // Release times within the window, newest first, as seconds from now.
+    let ages: Vec<i64> = sqlx::query_scalar(
+        "SELECT CAST(strftime('%s', 'now') AS INTEGER) - CAST(strftime('%s', released_at) AS INTEGER)
+        FROM handle_history
+        WHERE user_id = ? AND released_at > datetime('now', '-' || ? || ' seconds')
+        ORDER BY released_at DESC, id DESC
+        LIMIT ?",
+    )
+    .bind(user_id)
+    .bind(policy.change_window_secs)
+    .bind(policy.max_changes)
+    .fetch_all(conn)
+    .await?;
+    // Once the oldest of the last `max_changes` leaves the window, one is free.
+    Ok(match ages.last() {
+        Some(age) if ages.len() as i64 >= policy.max_changes => {
+            (policy.change_window_secs - age).max(1)
+        }
+        _ => 0,
+    })
+}
+
+/// `GET /v1/handles/{handle}`
+///
+/// Resolves a handle to its user. A handle the user has since changed
+/// resolves too, with `redirected_from` set so clients can redirect to the
+/// current one, as long as nobody else has claimed it.
+pub async fn lookup(
+    State(state): State<AppState>,
+    Path(raw): Path<String>,
+) -> Result<Response, AppError> {
+    let not_found = || AppError::NotFound {
+        code: "handle.not_found",
+        message: format!("No user has the handle '{}'.", raw),
+    };
+    let key = canonical(raw.trim());
+
+    let (user_id, redirected) = match key_owner(&state.db.handle, &key).await? {
+        Some(user_id) => (user_id, false),
+        None => {
+            let previous: Option<String> = sqlx::query_scalar(
+                "SELECT user_id FROM handle_history WHERE handle_key = ?
+                ORDER BY released_at DESC, id DESC LIMIT 1",
+            )
+            .bind(&key)
+            .fetch_optional(&state.db.handle)
+            .await?;
+            (previous.ok_or_else(not_found)?, true)
+        }
+    };
+    let user = match fetch_user(&state.db, &user_id).await {
+        Ok(user) => user,
+        Err(AppError::NotFound { .. }) => return Err(not_found()),
+        Err(e) => return Err(e),
+    };
+    let handle: String = sqlx::query_scalar("SELECT handle FROM handle_users WHERE user_id = ? LIMIT 1")
+        .bind(&user.user_id)
+        .fetch_optional(&state.db.handle)
+        .await?
+        .ok_or_else(not_found)?;
+
+    Ok(response::success(Some(json!({
+        "user_id": user.user_id,
+        "handle": handle,
+        "redirected_from": redirected.then_some(raw.trim()),
+    }))))
+}
+
+/// `GET /v1/handles/{handle}/availability`
+///
+/// Tells whether a handle can be claimed and, if not, why: `invalid` (with
+/// the field errors), `reserved`, `taken` or `cooldown` (recently released
+/// by another user).
+pub async fn availability(
+    State(state): State<AppState>,
+    Path(raw): Path<String>,
+) -> Result<Response, AppError> {
+    let handle = match state.handles.validate(&raw) {
+        Ok(handle) => handle,
+        Err(error) => {
+            return Ok(response::success(Some(json!({
+                "handle": raw,
+                "available": false,
+                "reason": "invalid",
+                "errors": [error],
+            }))));
+        }
+    };
+
+    let key = canonical(&handle);
+    let reason = if state.handles.is_reserved(&handle) {
+        Some("reserved")
+    } else if key_owner(&state.db.handle, &key).await?.is_some() {
+        Some("taken")
+    } else if cooling_down(&state.db.handle, &state.handles, &key, None).await? {
+        Some("cooldown")
+    } else {
+        None
+    };
+    Ok(response::success(Some(json!({
+        "handle": handle,
+        "available": reason.is_none(),
+        "reason": reason,
+    }))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetHandle {
+    handle: String,
+}
+
+/// `PUT /v1/users/{id}/handle`
+///
+/// Claims a handle for a user, replacing their current one, which moves to
+/// the handle history. Users may set their own, a limited number of times per
+/// window; setting anyone else's needs `users:write`. Reserved handles, and
+/// handles another user released within the cooldown, can only be assigned
+/// with the API token.
+pub async fn set_handle(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<SetHandle>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let target = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_self_or(&state, &caller, ip, &target, Permission::UsersWrite)?;
+
+    let handle = state
+        .handles
+        .validate(&body.handle)
+        .map_err(|e| AppError::Validation(vec![e]))?;
+    if state.handles.is_reserved(&handle) && !matches!(caller, Caller::Token { .. }) {
+        return Err(AppError::Conflict {
+            code: "handle.reserved",
+            message: "This handle is reserved.".to_string(),
+        });
+    }
+    let key = canonical(&handle);
+    let taken = || AppError::Conflict {
+        code: "handle.taken",
+        message: "This handle is already taken.".to_string(),
+    };
+
+    // The checks run in a write transaction taken up front, so concurrent
+    // changes are serialised and cannot each pass the cooldown and the
+    // change limit before any of them is recorded.
+    let mut tx = state.db.handle.begin_with("BEGIN IMMEDIATE").await?;
+    if key_owner(&mut *tx, &key).await?.is_some_and(|owner| owner != user_id) {
+        return Err(taken());
+    }
+    let is_token = matches!(caller, Caller::Token { .. });
+    if !is_token && cooling_down(&mut *tx, &state.handles, &key, Some(&user_id)).await? {
+        return Err(AppError::Conflict {
+            code: "handle.cooldown",
+            message: "This handle was released recently and cannot be claimed yet.".to_string(),
+        });
+    }
+
+    let current: Vec<(String, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT handle, handle_key, last_modified FROM handle_users
+        WHERE user_id = ? AND handle_key IS NOT ?",
+    )
+    .bind(&user_id)
+    .bind(&key)
+    .fetch_all(&mut *tx)
+    .await?;
+    let is_self = matches!(&caller, Caller::User(user) if user.user_id == user_id);
+    if is_self && !current.is_empty() {
+        let wait = change_wait(&mut *tx, &state.handles, &user_id).await?;
+        if wait > 0 {
+            return Err(AppError::Throttled {
+                code: "handle.change_throttled",
+                message: "Handle changed too often. Try again later.".to_string(),
+                retry_after: wait as u64,
+            });
+        }
+    }
+
+    // Changing only the case or look of the handle keeps the same key and
+    // is not recorded as a release.
+    for (old_handle, old_key, claimed_at) in &current {
+        sqlx::query(
+            "INSERT INTO handle_history (user_id, handle, handle_key, claimed_at)
+            VALUES (?, ?, ?, ?)",
+        )
+        .bind(&user_id)
+        .bind(old_handle)
+        .bind(old_key.clone().unwrap_or_else(|| canonical(old_handle)))
+        .bind(claimed_at)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("DELETE FROM handle_users WHERE user_id = ?")
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO handle_users (handle, user_id, handle_key) VALUES (?, ?, ?)")
+        .bind(&handle)
+        .bind(&user_id)
+        .bind(&key)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| match e {
+            // Claimed concurrently by someone else.
+            sqlx::Error::Database(db) if db.is_unique_violation() => taken(),
+            e => e.into(),
+        })?;
+    tx.commit().await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(caller.actor(), "handle.set", Outcome::Success)
+            .target_user(&user_id)
+            .ip(ip),
+    );
+
+    Ok(response::success(Some(json!({
+        "user_id": user_id,
+        "handle": handle,
+    }))))
+}
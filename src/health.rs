@@ -0,0 +1,66 @@
+// src/health.rs
+
+use crate::{error::AppError, response, AppState};
+use axum::{extract::State, response::Response};
+use serde_json::{json, Map, Value};
+use std::{path::Path, sync::atomic::Ordering};
+
+/// Liveness probe: answers as long as the process can serve requests.
+pub async fn healthz() -> Response {
+    response::success(Some(json!({ "status": "alive" })))
+}
+
+/// Readiness probe: checks every dependency and fails with 503 while any
+/// of them is down, or while the server is starting up or shutting down.
+pub async fn readyz(State(state): State<AppState>) -> Result<Response, AppError> {
+    let mut components = Map::new();
+    let mut ready = state.ready.load(Ordering::Acquire);
+    components.insert(
+        "server".to_string(),
+        component_status(ready, "not accepting traffic"),
+    );
+
+    for (name, pool) in state.db.all() {
+        let ok = sqlx::query("SELECT 1").execute(pool).await.is_ok();
+        ready &= ok;
+        components.insert(
+            format!("sqlite.{}", name),
+            component_status(ok, "query failed"),
+        );
+    }
+
+    let passwd_ok = tokio::fs::read("/opt/stardust/etc/passwd")
+        .await
+        .is_ok_and(|token| !token.is_empty());
+    ready &= passwd_ok;
+    components.insert(
+        "passwd".to_string(),
+        component_status(passwd_ok, "unreadable or empty"),
+    );
+
+    let public_ok = Path::new("/opt/stardust/public").is_dir();
+    ready &= public_ok;
+    components.insert(
+        "public".to_string(),
+        component_status(public_ok, "directory missing"),
+    );
+
+    let data = json!({ "components": components });
+    if ready {
+        Ok(response::success(Some(data)))
+    } else {
+        Err(AppError::Unavailable {
+            code: "health.not_ready",
+            message: "Service is not ready.".to_string(),
+            details: Some(data),
+        })
+    }
+}
+
+fn component_status(ok: bool, failure: &str) -> Value {
+    if ok {
+        json!({ "status": "ok" })
+    } else {
+        json!({ "status": "error", "error": failure })
+    }
+}
@@ -0,0 +1,180 @@
+// src/logging.rs
+
+use crate::{auth::Principal, rate_limiting::extract_client_ip, request_id};
+use axum::{extract::Request, http::header, middleware::Next, response::Response};
+use chrono::{SecondsFormat, Utc};
+pub use fancy_log::LogLevel;
+use http_body::Body as HttpBody;
+use serde::Serialize;
+use serde_json::json;
+use std::{env, sync::OnceLock, time::Instant};
+
+/// Output format for log lines, selected with `LOG_FORMAT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored, human-readable lines written by `fancy_log`.
+    Human,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+struct LoggingConfig {
+    level: LogLevel,
+    format: LogFormat,
+}
+
+static CONFIG: OnceLock<LoggingConfig> = OnceLock::new();
+
+/// Reads `LOG_LEVEL` (`error`, `warn`, `info`, `debug`) and `LOG_FORMAT`
+/// (`human`, `json`) from the environment. The level defaults to `debug`
+/// when `MODE=dev` and to `info` otherwise.
+pub fn init() {
+    let level = env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|val| serde_json::from_value::<LogLevel>(json!(val.trim().to_lowercase())).ok())
+        .unwrap_or_else(|| {
+            if env::var("MODE").is_ok_and(|mode| mode == "dev") {
+                LogLevel::Debug
+            } else {
+                LogLevel::Info
+            }
+        });
+    let format = match env::var("LOG_FORMAT").map(|val| val.trim().to_lowercase()) {
+        Ok(val) if val == "json" => LogFormat::Json,
+        _ => LogFormat::Human,
+    };
+
+    fancy_log::set_log_level(level);
+    let _ = CONFIG.set(LoggingConfig { level, format });
+}
+
+fn config() -> &'static LoggingConfig {
+    CONFIG.get_or_init(|| LoggingConfig {
+        level: LogLevel::Info,
+        format: LogFormat::Human,
+    })
+}
+
+fn level_rank(level: LogLevel) -> u8 {
+    match level {
+        LogLevel::Error => 1,
+        LogLevel::Warn => 2,
+        LogLevel::Info => 3,
+        LogLevel::Debug => 4,
+    }
+}
+
+fn level_name(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug",
+    }
+}
+
+/// Writes one JSON line, honoring the configured level.
+fn emit_json(level: LogLevel, value: &serde_json::Value) {
+    if level_rank(level) > level_rank(config().level) {
+        return;
+    }
+    match level {
+        LogLevel::Warn | LogLevel::Error => eprintln!("{}", value),
+        _ => println!("{}", value),
+    }
+}
+
+/// Logs through `fancy_log`, prefixing the line with the current request ID
+/// when called while a request is being handled.
+pub fn log(level: LogLevel, content: &str) {
+    let request_id = request_id::current();
+
+    if config().format == LogFormat::Json {
+        let mut line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "level": level_name(level),
+            "message": content,
+        });
+        if let Some(id) = request_id {
+            line["request_id"] = json!(id);
+        }
+        emit_json(level, &line);
+        return;
+    }
+
+    match request_id {
+        Some(id) => fancy_log::log(level, &format!("[{}] {}", id, content)),
+        None => fancy_log::log(level, content),
+    }
+}
+
+/// One access log entry, emitted after the response has been produced.
+#[derive(Serialize, Debug)]
+struct AccessLogEntry {
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: f64,
+    client_ip: Option<String>,
+    request_id: Option<String>,
+    principal: Option<String>,
+    bytes: Option<u64>,
+}
+
+/// Emits one structured line per request with its method, path, status,
+/// latency, client IP, request ID, authenticated principal and body size.
+pub async fn access_log_middleware(req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.uri().path().to_string();
+    let client_ip = extract_client_ip(&req).map(|ip| ip.to_string());
+
+    let response = next.run(req).await;
+
+    let entry = AccessLogEntry {
+        method,
+        path,
+        status: response.status().as_u16(),
+        latency_ms: (started.elapsed().as_secs_f64() * 1000.0 * 100.0).round() / 100.0,
+        client_ip,
+        request_id: request_id::current(),
+        principal: response.extensions().get::<Principal>().map(|p| p.0.clone()),
+        bytes: response.body().size_hint().exact().or_else(|| {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        }),
+    };
+
+    if config().format == LogFormat::Json {
+        let mut line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "level": "info",
+            "type": "access",
+        });
+        if let (Some(line), Ok(serde_json::Value::Object(fields))) =
+            (line.as_object_mut(), serde_json::to_value(&entry))
+        {
+            line.extend(fields);
+        }
+        emit_json(LogLevel::Info, &line);
+    } else {
+        log(
+            LogLevel::Info,
+            &format!(
+                "{} {} {} {}ms ip={} principal={} bytes={}",
+                entry.method,
+                entry.path,
+                entry.status,
+                entry.latency_ms,
+                entry.client_ip.as_deref().unwrap_or("-"),
+                entry.principal.as_deref().unwrap_or("-"),
+                entry.bytes.map_or_else(|| "-".to_string(), |b| b.to_string()),
+            ),
+        );
+    }
+
+    response
+}
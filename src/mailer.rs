@@ -0,0 +1,736 @@
+// src/mailer.rs
+
+use crate::{
+    logging::{log, LogLevel},
+    sqlite::DATA_DIR,
+    templates::{EmailKind, EmailTemplates},
+};
+use anyhow::{anyhow, bail, Context};
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use rand::RngCore;
+use sqlx::{Pool, Sqlite};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Sender used unless `MAIL_FROM` is set.
+const DEFAULT_FROM: &str = "Stardust <noreply@localhost>";
+
+/// Deliveries attempted before an email is given up on.
+const MAX_ATTEMPTS: i64 = 8;
+/// Delay before the first retry; doubled on every further failure.
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 3600;
+
+/// Seconds an email may wait undelivered before it is expired, unless
+/// `MAIL_PENDING_TTL_SECS` is set. The codes and links it carries have long
+/// stopped working by then.
+const DEFAULT_PENDING_TTL_SECS: i64 = 24 * 3600;
+
+/// Emails picked up by one pass of the worker.
+const BATCH_SIZE: i64 = 20;
+/// How often the worker looks for due retries when nothing new is queued.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An email to be queued for delivery.
+#[derive(Debug)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct QueuedEmail {
+    id: i64,
+    recipient: String,
+    subject: String,
+    text_body: String,
+    html_body: Option<String>,
+    attempts: i64,
+}
+
+/// Durable outbound email queue stored in `mail.sqlite`.
+///
+/// `enqueue` only writes the email to the outbox; a background worker
+/// delivers it through the configured transport, retrying failures with
+/// exponential backoff. Queued emails survive restarts, and delivery is
+/// at-least-once: a crash between sending and recording the result resends.
+/// Bodies are cleared once an email is sent, has failed or has expired, since
+/// they may hold one-time codes and links.
+#[derive(Clone)]
+pub struct Mailer {
+    pool: Pool<Sqlite>,
+    templates: Arc<EmailTemplates>,
+    wake: Arc<Notify>,
+}
+
+impl Mailer {
+    pub fn new(pool: Pool<Sqlite>, templates: EmailTemplates) -> Self {
+        Self {
+            pool,
+            templates: Arc::new(templates),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn templates(&self) -> &EmailTemplates {
+        &self.templates
+    }
+
+    /// Renders `kind` in `locale` and queues it for `to`.
+    pub async fn enqueue_template(
+        &self,
+        to: &str,
+        kind: EmailKind,
+        locale: &str,
+        context: tera::Context,
+    ) -> anyhow::Result<i64> {
+        let rendered = self.templates.render(kind, locale, context)?;
+        self.enqueue(OutgoingEmail {
+            to: to.to_string(),
+            subject: rendered.subject,
+            text: rendered.text,
+            html: Some(rendered.html),
+        })
+        .await
+    }
+
+    pub async fn enqueue(&self, email: OutgoingEmail) -> anyhow::Result<i64> {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO mail_outbox (recipient, subject, text_body, html_body)
+            VALUES (?, ?, ?, ?)
+            RETURNING id",
+        )
+        .bind(&email.to)
+        .bind(&email.subject)
+        .bind(&email.text)
+        .bind(&email.html)
+        .fetch_one(&self.pool)
+        .await?;
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    /// Starts the delivery worker. Without a configured transport, emails
+    /// stay queued until the server is restarted with one, or until they
+    /// expire after `MAIL_PENDING_TTL_SECS`.
+    pub fn spawn_worker(&self) -> anyhow::Result<()> {
+        let delivery = match Transport::from_env()? {
+            Some(transport) => {
+                let from: Mailbox = env::var("MAIL_FROM")
+                    .unwrap_or_else(|_| DEFAULT_FROM.to_string())
+                    .parse()
+                    .context("Invalid MAIL_FROM")?;
+                log(
+                    LogLevel::Info,
+                    &format!("Mail transport: {}, sending as {}", transport.describe(), from),
+                );
+                Some((transport, from))
+            }
+            None => {
+                log(
+                    LogLevel::Warn,
+                    "No mail transport configured (set SMTP_HOST or MAIL_TRANSPORT). Emails will be queued but not sent.",
+                );
+                None
+            }
+        };
+        let pending_ttl = env::var("MAIL_PENDING_TTL_SECS")
+            .ok()
+            .and_then(|val| val.trim().parse::<i64>().ok())
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_PENDING_TTL_SECS);
+
+        let mailer = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = mailer.expire_stale(pending_ttl).await {
+                    log(LogLevel::Error, &format!("Expiring stale emails failed: {:#}", e));
+                }
+                if let Some((transport, from)) = &delivery
+                    && let Err(e) = mailer.deliver_due(transport, from).await
+                {
+                    log(LogLevel::Error, &format!("Mail delivery pass failed: {:#}", e));
+                }
+                tokio::select! {
+                    _ = mailer.wake.notified() => {}
+                    _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Sends every email whose next attempt is due, in batches.
+    async fn deliver_due(&self, transport: &Transport, from: &Mailbox) -> anyhow::Result<()> {
+        loop {
+            let due: Vec<QueuedEmail> = sqlx::query_as(
+                "SELECT id, recipient, subject, text_body, html_body, attempts FROM mail_outbox
+                WHERE status = 'pending' AND next_attempt_at <= datetime('now')
+                ORDER BY next_attempt_at, id
+                LIMIT ?",
+            )
+            .bind(BATCH_SIZE)
+            .fetch_all(&self.pool)
+            .await?;
+            if due.is_empty() {
+                return Ok(());
+            }
+            for email in due {
+                let id = email.id;
+                let attempts = email.attempts + 1;
+                match send(transport, from, email).await {
+                    Ok(()) => self.mark_sent(id).await?,
+                    Err(Failure::Permanent(e)) => self.mark_failed(id, attempts, &e).await?,
+                    Err(Failure::Transient(e)) if attempts >= MAX_ATTEMPTS => {
+                        self.mark_failed(id, attempts, &e).await?
+                    }
+                    Err(Failure::Transient(e)) => self.schedule_retry(id, attempts, &e).await?,
+                }
+            }
+        }
+    }
+
+    /// Bodies of sent emails are dropped, since they may hold one-time codes.
+    async fn mark_sent(&self, id: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE mail_outbox
+            SET status = 'sent', sent_at = CURRENT_TIMESTAMP, attempts = attempts + 1,
+                last_error = NULL, text_body = '', html_body = NULL
+            WHERE id = ?",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: i64, attempts: i64, error: &anyhow::Error) -> anyhow::Result<()> {
+        log(
+            LogLevel::Error,
+            &format!("Giving up on email {} after {} attempt(s): {:#}", id, attempts, error),
+        );
+        sqlx::query(
+            "UPDATE mail_outbox
+            SET status = 'failed', attempts = ?, last_error = ?, text_body = '', html_body = NULL
+            WHERE id = ?",
+        )
+        .bind(attempts)
+        .bind(format!("{:#}", error))
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Gives up on emails that have waited longer than `ttl_secs`, e.g.
+    /// because no transport is configured, and drops their bodies.
+    async fn expire_stale(&self, ttl_secs: i64) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            "UPDATE mail_outbox
+            SET status = 'expired', last_error = 'Not delivered in time', text_body = '', html_body = NULL
+            WHERE status = 'pending' AND created_at <= datetime('now', '-' || ? || ' seconds')",
+        )
+        .bind(ttl_secs)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() > 0 {
+            log(
+                LogLevel::Warn,
+                &format!("Expired {} undelivered email(s)", result.rows_affected()),
+            );
+        }
+        Ok(())
+    }
+
+    async fn schedule_retry(&self, id: i64, attempts: i64, error: &anyhow::Error) -> anyhow::Result<()> {
+        let delay = BASE_BACKOFF_SECS
+            .saturating_mul(1 << (attempts - 1).min(20))
+            .min(MAX_BACKOFF_SECS);
+        log(
+            LogLevel::Warn,
+            &format!("Email {} failed (attempt {}), retrying in {}s: {:#}", id, attempts, delay, error),
+        );
+        sqlx::query(
+            "UPDATE mail_outbox
+            SET attempts = ?, last_error = ?, next_attempt_at = datetime('now', '+' || ? || ' seconds')
+            WHERE id = ?",
+        )
+        .bind(attempts)
+        .bind(format!("{:#}", error))
+        .bind(delay)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+enum Failure {
+    /// Will fail again no matter how often it is retried, e.g. a malformed address.
+    Permanent(anyhow::Error),
+    Transient(anyhow::Error),
+}
+
+async fn send(transport: &Transport, from: &Mailbox, email: QueuedEmail) -> Result<(), Failure> {
+    let to: Mailbox = email
+        .recipient
+        .parse()
+        .map_err(|e| Failure::Permanent(anyhow!("Invalid recipient {}: {}", email.recipient, e)))?;
+    let builder = Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .message_id(None);
+    let message = match email.html_body {
+        Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.text_body, html)),
+        None => builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.text_body),
+    }
+    .map_err(|e| Failure::Permanent(anyhow!("Failed to build message: {}", e)))?;
+
+    transport.send(message).await
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, usually on port 587.
+    StartTls,
+    /// TLS from the first byte, usually on port 465.
+    Tls,
+    /// No encryption, for local relays only.
+    None,
+}
+
+enum Transport {
+    Smtp {
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+        host: String,
+        port: u16,
+        security: SmtpSecurity,
+    },
+    /// Writes each email as an `.eml` file into a maildir-style directory:
+    /// written under `tmp/`, then moved into `new/` once complete.
+    File(PathBuf),
+}
+
+impl Transport {
+    /// Reads the transport from `MAIL_TRANSPORT` (`smtp`, `file` or `none`).
+    /// When unset, SMTP is used if `SMTP_HOST` is set, the file transport in
+    /// dev mode, and none otherwise.
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let kind = match env::var("MAIL_TRANSPORT") {
+            Ok(kind) => kind.trim().to_lowercase(),
+            Err(_) if env::var("SMTP_HOST").is_ok() => "smtp".to_string(),
+            Err(_) if env::var("MODE").is_ok_and(|mode| mode == "dev") => "file".to_string(),
+            Err(_) => "none".to_string(),
+        };
+        match kind.as_str() {
+            "smtp" => Self::smtp_from_env().map(Some),
+            "file" => {
+                let dir = env::var("MAIL_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| PathBuf::from(DATA_DIR).join("mail"));
+                for sub in ["tmp", "new"] {
+                    std::fs::create_dir_all(dir.join(sub))
+                        .with_context(|| format!("Failed to create {}", dir.join(sub).display()))?;
+                }
+                Ok(Some(Transport::File(dir)))
+            }
+            "none" => Ok(None),
+            other => bail!("Unknown MAIL_TRANSPORT: {}", other),
+        }
+    }
+
+    /// `SMTP_HOST`, `SMTP_PORT`, `SMTP_SECURITY` (`starttls`, `tls` or `none`,
+    /// default `starttls`), and optionally `SMTP_USERNAME`/`SMTP_PASSWORD`.
+    fn smtp_from_env() -> anyhow::Result<Self> {
+        let host = env::var("SMTP_HOST").context("SMTP_HOST is required for the SMTP transport")?;
+        let security = match env::var("SMTP_SECURITY").as_deref().map(str::trim) {
+            Ok("starttls") | Err(_) => SmtpSecurity::StartTls,
+            Ok("tls") => SmtpSecurity::Tls,
+            Ok("none") => SmtpSecurity::None,
+            Ok(other) => bail!("Unknown SMTP_SECURITY: {}", other),
+        };
+        let default_port = match security {
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+        };
+        let port = match env::var("SMTP_PORT") {
+            Ok(port) => port.trim().parse().context("Invalid SMTP_PORT")?,
+            Err(_) => default_port,
+        };
+
+        let mut builder = match security {
+            SmtpSecurity::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+        }
+        .port(port);
+        if let Ok(username) = env::var("SMTP_USERNAME") {
+            let password = env::var("SMTP_PASSWORD").unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(Transport::Smtp {
+            transport: builder.build(),
+            host,
+            port,
+            security,
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Transport::Smtp {
+                host,
+                port,
+                security,
+                ..
+            } => format!("smtp://{}:{} ({:?})", host, port, security),
+            Transport::File(dir) => format!("file {}", dir.display()),
+        }
+    }
+
+    async fn send(&self, message: Message) -> Result<(), Failure> {
+        match self {
+            // 5xx replies, such as an unknown mailbox, will not succeed on retry.
+            Transport::Smtp { transport, .. } => match transport.send(message).await {
+                Ok(_) => Ok(()),
+                Err(e) if e.is_permanent() => Err(Failure::Permanent(e.into())),
+                Err(e) => Err(Failure::Transient(e.into())),
+            },
+            Transport::File(dir) => Self::write_file(dir, message)
+                .await
+                .map_err(Failure::Transient),
+        }
+    }
+
+    async fn write_file(dir: &Path, message: Message) -> anyhow::Result<()> {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        let name = format!(
+            "{}.{}.eml",
+            chrono::Utc::now().timestamp_millis(),
+            hex::encode(unique)
+        );
+        let tmp = dir.join("tmp").join(&name);
+        tokio::fs::write(&tmp, message.formatted()).await?;
+        tokio::fs::rename(&tmp, dir.join("new").join(&name)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// What the SMTP stand-in answers to `RCPT TO` and after `DATA`.
+    #[derive(Clone, Copy)]
+    struct Replies {
+        rcpt: &'static str,
+        data: &'static str,
+    }
+
+    const ACCEPT: Replies = Replies {
+        rcpt: "250 2.1.5 OK",
+        data: "250 2.0.0 Queued",
+    };
+
+    /// A minimal SMTP server on a random local port. Returns the port and
+    /// the messages it has accepted.
+    async fn smtp_stand_in(replies: Replies) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let messages = received.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(smtp_session(stream, replies, messages.clone()));
+            }
+        });
+        (port, received)
+    }
+
+    async fn smtp_session(
+        stream: TcpStream,
+        replies: Replies,
+        messages: Arc<Mutex<Vec<String>>>,
+    ) -> std::io::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 stand-in ESMTP\r\n").await?;
+        while let Some(line) = lines.next_line().await? {
+            let command = line.to_ascii_uppercase();
+            let reply = if command.starts_with("EHLO") {
+                "250-stand-in\r\n250 8BITMIME"
+            } else if command.starts_with("RCPT") {
+                replies.rcpt
+            } else if command.starts_with("DATA") {
+                write.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+                let mut message = String::new();
+                while let Some(line) = lines.next_line().await? {
+                    if line == "." {
+                        break;
+                    }
+                    message.push_str(&line);
+                    message.push('\n');
+                }
+                if replies.data.starts_with('2') {
+                    messages.lock().unwrap().push(message);
+                }
+                replies.data
+            } else if command.starts_with("QUIT") {
+                write.write_all(b"221 Bye\r\n").await?;
+                return Ok(());
+            } else {
+                "250 OK"
+            };
+            write.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    fn smtp_transport(port: u16) -> Transport {
+        Transport::Smtp {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build(),
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        env::temp_dir().join(format!("stardust-mailer-{}", hex::encode(unique)))
+    }
+
+    async fn mailer() -> Mailer {
+        let pool = sqlite::initialize_mail_db(&temp_dir()).await.unwrap();
+        Mailer::new(pool, EmailTemplates::empty())
+    }
+
+    fn from() -> Mailbox {
+        DEFAULT_FROM.parse().unwrap()
+    }
+
+    fn email(to: &str) -> OutgoingEmail {
+        OutgoingEmail {
+            to: to.to_string(),
+            subject: "Your code".to_string(),
+            text: "Your code is 123456.".to_string(),
+            html: Some("<p>Your code is <b>123456</b>.</p>".to_string()),
+        }
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct Row {
+        status: String,
+        attempts: i64,
+        text_body: String,
+        html_body: Option<String>,
+        last_error: Option<String>,
+        /// Seconds until the next attempt is due.
+        due_in: i64,
+    }
+
+    async fn row(mailer: &Mailer, id: i64) -> Row {
+        sqlx::query_as(
+            "SELECT status, attempts, text_body, html_body, last_error,
+                CAST(strftime('%s', next_attempt_at) AS INTEGER)
+                    - CAST(strftime('%s', 'now') AS INTEGER) AS due_in
+            FROM mail_outbox WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_one(&mailer.pool)
+        .await
+        .unwrap()
+    }
+
+    async fn make_due(mailer: &Mailer, id: i64) {
+        sqlx::query("UPDATE mail_outbox SET next_attempt_at = datetime('now') WHERE id = ?")
+            .bind(id)
+            .execute(&mailer.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn enqueued_email_is_delivered_over_smtp() {
+        let (port, received) = smtp_stand_in(ACCEPT).await;
+        let mailer = mailer().await;
+        let id = mailer.enqueue(email("alice@example.com")).await.unwrap();
+        assert_eq!(row(&mailer, id).await.status, "pending");
+
+        mailer.deliver_due(&smtp_transport(port), &from()).await.unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].contains("To: alice@example.com"));
+        assert!(received[0].contains("Subject: Your code"));
+        assert!(received[0].contains("Your code is 123456."));
+        assert!(received[0].contains("text/html"));
+
+        let row = row(&mailer, id).await;
+        assert_eq!(row.status, "sent");
+        assert_eq!(row.attempts, 1);
+        assert_eq!(row.text_body, "");
+        assert_eq!(row.html_body, None);
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried_with_backoff() {
+        let (port, received) = smtp_stand_in(Replies {
+            data: "451 4.3.0 Try again later",
+            ..ACCEPT
+        })
+        .await;
+        let mailer = mailer().await;
+        let transport = smtp_transport(port);
+        let id = mailer.enqueue(email("alice@example.com")).await.unwrap();
+
+        mailer.deliver_due(&transport, &from()).await.unwrap();
+        let first = row(&mailer, id).await;
+        assert_eq!(first.status, "pending");
+        assert_eq!(first.attempts, 1);
+        assert!((BASE_BACKOFF_SECS - 1..=BASE_BACKOFF_SECS).contains(&first.due_in));
+        assert!(first.last_error.is_some());
+        assert_eq!(first.text_body, "Your code is 123456.");
+
+        // Not retried before it is due.
+        mailer.deliver_due(&transport, &from()).await.unwrap();
+        assert_eq!(row(&mailer, id).await.attempts, 1);
+
+        make_due(&mailer, id).await;
+        mailer.deliver_due(&transport, &from()).await.unwrap();
+        let second = row(&mailer, id).await;
+        assert_eq!(second.attempts, 2);
+        assert!((2 * BASE_BACKOFF_SECS - 1..=2 * BASE_BACKOFF_SECS).contains(&second.due_in));
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (port, _) = smtp_stand_in(Replies {
+            data: "451 4.3.0 Try again later",
+            ..ACCEPT
+        })
+        .await;
+        let mailer = mailer().await;
+        let id = mailer.enqueue(email("alice@example.com")).await.unwrap();
+        sqlx::query("UPDATE mail_outbox SET attempts = ? WHERE id = ?")
+            .bind(MAX_ATTEMPTS - 1)
+            .bind(id)
+            .execute(&mailer.pool)
+            .await
+            .unwrap();
+
+        mailer.deliver_due(&smtp_transport(port), &from()).await.unwrap();
+
+        let row = row(&mailer, id).await;
+        assert_eq!(row.status, "failed");
+        assert_eq!(row.attempts, MAX_ATTEMPTS);
+        assert_eq!(row.text_body, "");
+        assert_eq!(row.html_body, None);
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_is_not_retried() {
+        let (port, received) = smtp_stand_in(Replies {
+            rcpt: "550 5.1.1 No such user",
+            ..ACCEPT
+        })
+        .await;
+        let mailer = mailer().await;
+        let id = mailer.enqueue(email("nobody@example.com")).await.unwrap();
+
+        mailer.deliver_due(&smtp_transport(port), &from()).await.unwrap();
+
+        let row = row(&mailer, id).await;
+        assert_eq!(row.status, "failed");
+        assert_eq!(row.attempts, 1);
+        assert!(row.last_error.is_some_and(|e| e.contains("550")));
+        assert_eq!(row.text_body, "");
+        assert_eq!(row.html_body, None);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_recipient_fails_without_connecting() {
+        let (port, received) = smtp_stand_in(ACCEPT).await;
+        let mailer = mailer().await;
+        let id = mailer.enqueue(email("not an address")).await.unwrap();
+
+        mailer.deliver_due(&smtp_transport(port), &from()).await.unwrap();
+
+        assert_eq!(row(&mailer, id).await.status, "failed");
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_transport_writes_into_new() {
+        let dir = temp_dir();
+        for sub in ["tmp", "new"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let mailer = mailer().await;
+        let id = mailer.enqueue(email("alice@example.com")).await.unwrap();
+
+        mailer
+            .deliver_due(&Transport::File(dir.clone()), &from())
+            .await
+            .unwrap();
+
+        assert_eq!(row(&mailer, id).await.status, "sent");
+        assert_eq!(std::fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        let files: Vec<_> = std::fs::read_dir(dir.join("new"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].extension().is_some_and(|ext| ext == "eml"));
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.contains("Subject: Your code"));
+    }
+
+    #[tokio::test]
+    async fn stale_pending_email_expires_and_drops_its_body() {
+        let mailer = mailer().await;
+        let stale = mailer.enqueue(email("alice@example.com")).await.unwrap();
+        let fresh = mailer.enqueue(email("bob@example.com")).await.unwrap();
+        sqlx::query("UPDATE mail_outbox SET created_at = datetime('now', '-2 hours') WHERE id = ?")
+            .bind(stale)
+            .execute(&mailer.pool)
+            .await
+            .unwrap();
+
+        mailer.expire_stale(3600).await.unwrap();
+
+        let stale = row(&mailer, stale).await;
+        assert_eq!(stale.status, "expired");
+        assert_eq!(stale.text_body, "");
+        assert_eq!(stale.html_body, None);
+        assert_eq!(row(&mailer, fresh).await.status, "pending");
+    }
+}
@@ -1,31 +1,67 @@
 // src/main.rs
 
+mod account;
+mod audit;
 mod auth;
 mod bootstrap;
 mod cors;
+mod email;
+mod email_login;
+mod error;
+mod extract;
+mod handle;
+mod health;
+mod logging;
+mod mailer;
+mod metrics;
+mod mfa;
 mod rate_limiting;
+mod rbac;
+mod request_id;
 mod response;
 mod router;
+mod session;
 mod sqlite;
+mod users;
 mod passwd;
+mod passkey;
+mod password;
+mod password_policy;
+mod password_reset;
 mod public;
+mod templates;
 
 use crate::{
-    cors::cors_middleware,
+    audit::Audit,
+    cors::CorsConfig,
+    handle::HandlePolicy,
+    logging::{log, LogLevel},
+    mailer::Mailer,
+    metrics::Metrics,
+    password_policy::PasswordPolicy,
     rate_limiting::{RateLimitLayer, RateLimiterState},
-    sqlite::initialize_databases,
+    sqlite::{initialize_databases, Databases},
+    templates::EmailTemplates,
 };
 use axum::middleware;
 use base64::{engine::general_purpose, Engine as _};
 use dotenvy::dotenv;
-use fancy_log::{log, set_log_level, LogLevel};
 use rand::RngCore;
-use std::{fs, io::Write, path::Path, sync::{Arc, RwLock}};
+use std::{fs, io::Write, path::Path, sync::{atomic::AtomicBool, Arc, RwLock}};
 
-// The application's shared state, now holding the API token.
+// The application's shared state: the API token, database pools, metrics,
+// the audit log, the outbound mail queue, the handle and password rules and
+// whether the server is ready to take traffic.
 #[derive(Clone)]
 pub struct AppState {
     api_token: Arc<RwLock<String>>,
+    db: Databases,
+    metrics: Metrics,
+    audit: Audit,
+    mailer: Mailer,
+    handles: Arc<HandlePolicy>,
+    passwords: Arc<PasswordPolicy>,
+    ready: Arc<AtomicBool>,
 }
 
 /// Sets up the required directory structure and loads or creates the API token.
@@ -79,7 +115,7 @@ fn setup_and_load_token() -> String {
         );
         let mut key = [0u8; 64];
         rand::rng().fill_bytes(&mut key);
-        let token = general_purpose::STANDARD.encode(&key);
+        let token = general_purpose::STANDARD.encode(key);
 
         let mut file = fs::File::create(&passwd_file).expect("Failed to create passwd file");
         file.write_all(token.as_bytes())
@@ -95,7 +131,7 @@ fn setup_and_load_token() -> String {
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    set_log_level(LogLevel::Debug);
+    logging::init();
 
     // Setup file structure and load the token at startup.
     let api_token = setup_and_load_token();
@@ -106,28 +142,73 @@ async fn main() {
         return;
     }
 
-    // Initialize databases
-    if let Err(e) = initialize_databases().await {
-        log(LogLevel::Error, &format!("Failed to initialize databases: {}", e));
+    // Setup email templates, keeping any the operator has customized
+    if let Err(e) = templates::setup_templates_directory() {
+        log(LogLevel::Error, &format!("Failed to setup templates directory: {}", e));
         return;
     }
+    let templates = match EmailTemplates::load() {
+        Ok(templates) => templates,
+        Err(e) => {
+            log(LogLevel::Error, &format!("Failed to load email templates: {:#}", e));
+            return;
+        }
+    };
+
+    let handles = match HandlePolicy::from_env() {
+        Ok(handles) => handles,
+        Err(e) => {
+            log(LogLevel::Error, &format!("Failed to load handle policy: {:#}", e));
+            return;
+        }
+    };
+
+    // Initialize databases
+    let db = match initialize_databases().await {
+        Ok(db) => db,
+        Err(e) => {
+            log(LogLevel::Error, &format!("Failed to initialize databases: {}", e));
+            return;
+        }
+    };
 
     // Create shared state.
+    let metrics = Metrics::new();
+    let audit = Audit::new(db.audit.clone());
+    let mailer = Mailer::new(db.mail.clone(), templates);
+    if let Err(e) = mailer.spawn_worker() {
+        log(LogLevel::Error, &format!("Failed to start mailer: {:#}", e));
+        return;
+    }
     let app_state = AppState {
         api_token: Arc::new(RwLock::new(api_token)),
+        db,
+        metrics: metrics.clone(),
+        audit: audit.clone(),
+        mailer,
+        handles: Arc::new(handles),
+        passwords: Arc::new(PasswordPolicy::from_env()),
+        ready: Arc::new(AtomicBool::new(false)),
     };
+    mfa::required_level();
     let rate_limiter_state = RateLimiterState::new();
+    users::spawn_purge_job(audit.clone());
+    let cors_config = Arc::new(CorsConfig::from_env());
 
     // Build the final app by applying middleware layers and providing state.
-    // Note: auth_middleware is now applied inside router::create_router() only to API routes
-    let app = router::create_router()
-        .layer(RateLimitLayer::new(rate_limiter_state))
-        .layer(middleware::from_fn(cors_middleware))
-        .with_state(app_state);
+    // Note: auth, CORS and rate limiting are applied per route group inside router::create_router()
+    let rate_limit = RateLimitLayer::new(rate_limiter_state, metrics.clone(), audit);
+    let app = router::create_router(app_state.clone(), cors_config, rate_limit)
+        .layer(middleware::from_fn(response::negotiate_error_format))
+        .layer(middleware::from_fn_with_state(metrics, metrics::metrics_middleware))
+        .layer(middleware::from_fn(logging::access_log_middleware))
+        .layer(middleware::from_fn(request_id::request_id_middleware))
+        .with_state(app_state.clone());
+    let admin_app = router::create_admin_router().with_state(app_state.clone());
 
     log(LogLevel::Info, "Starting server...");
 
-    if let Err(e) = bootstrap::run(app).await {
+    if let Err(e) = bootstrap::run(app, admin_app, app_state.ready).await {
         let error_message = format!("Server failed to start: {}", e);
         log(LogLevel::Error, &error_message);
     }
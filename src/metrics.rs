@@ -0,0 +1,264 @@
+// src/metrics.rs
+
+use crate::{error::AppError, sqlite::MAX_CONNECTIONS, AppState};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Label used for requests that did not match a route (static files, early rejections).
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricsInner {
+    /// Keyed by (method, route, status).
+    requests: HashMap<(String, String, u16), u64>,
+    /// Keyed by (method, route).
+    latency: HashMap<(String, String), Histogram>,
+    rate_limit_rejections: u64,
+    /// Keyed by `AppError` code.
+    auth_failures: HashMap<&'static str, u64>,
+}
+
+/// In-process counters, rendered in Prometheus text format by `metrics_handler`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricsInner>>,
+}
+
+/// The route template a request matched, copied to the response so the outer
+/// metrics layer can label it.
+#[derive(Clone)]
+struct MatchedRoute(String);
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record_request(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner
+            .requests
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+        inner
+            .latency
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .observe(seconds);
+    }
+
+    pub fn record_rate_limit_rejection(&self) {
+        self.inner.lock().unwrap().rate_limit_rejections += 1;
+    }
+
+    pub fn record_auth_failure(&self, error: &AppError) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .auth_failures
+            .entry(error.code())
+            .or_default() += 1;
+    }
+
+    /// Renders the in-process counters. Database gauges are appended by the handler.
+    fn render(&self, out: &mut String) {
+        let inner = self.inner.lock().unwrap();
+
+        let _ = writeln!(out, "# HELP stardust_http_requests_total Total HTTP requests handled.");
+        let _ = writeln!(out, "# TYPE stardust_http_requests_total counter");
+        let mut requests: Vec<_> = inner.requests.iter().collect();
+        requests.sort();
+        for ((method, route, status), count) in requests {
+            let _ = writeln!(
+                out,
+                "stardust_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method,
+                escape_label(route),
+                status,
+                count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP stardust_http_request_duration_seconds HTTP request latency.");
+        let _ = writeln!(out, "# TYPE stardust_http_request_duration_seconds histogram");
+        let mut latency: Vec<_> = inner.latency.iter().collect();
+        latency.sort_by(|a, b| a.0.cmp(b.0));
+        for ((method, route), histogram) in latency {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, escape_label(route));
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "stardust_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "stardust_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(out, "stardust_http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "stardust_http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+
+        let _ = writeln!(out, "# HELP stardust_rate_limit_rejections_total Requests rejected by the rate limiter.");
+        let _ = writeln!(out, "# TYPE stardust_rate_limit_rejections_total counter");
+        let _ = writeln!(out, "stardust_rate_limit_rejections_total {}", inner.rate_limit_rejections);
+
+        let _ = writeln!(out, "# HELP stardust_auth_failures_total Failed authentication attempts by reason.");
+        let _ = writeln!(out, "# TYPE stardust_auth_failures_total counter");
+        let mut failures: Vec<_> = inner.auth_failures.iter().collect();
+        failures.sort();
+        for (reason, count) in failures {
+            let _ = writeln!(out, "stardust_auth_failures_total{{reason=\"{}\"}} {}", reason, count);
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Records request count and latency per method, route and status.
+/// Must wrap the whole router so that rejected and unmatched requests are counted too.
+pub async fn metrics_middleware(State(metrics): State<Metrics>, req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
+
+    let response = next.run(req).await;
+
+    let route = response
+        .extensions()
+        .get::<MatchedRoute>()
+        .map(|r| r.0.as_str())
+        .unwrap_or(UNMATCHED_ROUTE);
+    metrics.record_request(
+        &method,
+        route,
+        response.status().as_u16(),
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Route layer that exposes the matched route template to `metrics_middleware`.
+/// Using the template instead of the raw path keeps label cardinality bounded.
+pub async fn matched_route_middleware(req: Request, next: Next) -> Response {
+    let route = req.extensions().get::<MatchedPath>().map(|p| p.as_str().to_string());
+    let mut response = next.run(req).await;
+    if let Some(route) = route {
+        response.extensions_mut().insert(MatchedRoute(route));
+    }
+    response
+}
+
+/// Serves all metrics in Prometheus text format. Mounted on the admin listener only.
+pub async fn metrics_handler(State(state): State<AppState>) -> Result<Response, AppError> {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+
+    let _ = writeln!(out, "# HELP stardust_sqlite_pool_connections SQLite pool connections by state.");
+    let _ = writeln!(out, "# TYPE stardust_sqlite_pool_connections gauge");
+    for (name, pool) in state.db.all() {
+        let size = pool.size();
+        let idle = pool.num_idle() as u32;
+        let _ = writeln!(
+            out,
+            "stardust_sqlite_pool_connections{{database=\"{}\",state=\"idle\"}} {}",
+            name, idle
+        );
+        let _ = writeln!(
+            out,
+            "stardust_sqlite_pool_connections{{database=\"{}\",state=\"in_use\"}} {}",
+            name,
+            size.saturating_sub(idle)
+        );
+    }
+    let _ = writeln!(out, "# HELP stardust_sqlite_pool_max_connections SQLite pool capacity.");
+    let _ = writeln!(out, "# TYPE stardust_sqlite_pool_max_connections gauge");
+    for (name, _) in state.db.all() {
+        let _ = writeln!(
+            out,
+            "stardust_sqlite_pool_max_connections{{database=\"{}\"}} {}",
+            name, MAX_CONNECTIONS
+        );
+    }
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(&state.db.account)
+        .await?;
+    let _ = writeln!(out, "# HELP stardust_users Registered user accounts.");
+    let _ = writeln!(out, "# TYPE stardust_users gauge");
+    let _ = writeln!(out, "stardust_users {}", users);
+
+    let credentials = [
+        ("email", &state.db.email, "email_users"),
+        ("handle", &state.db.handle, "handle_users"),
+        ("password", &state.db.passwd, "passwd_users"),
+        ("totp", &state.db.totp, "totp_users"),
+        ("passkey", &state.db.passkey, "passkey_users"),
+        ("recovery", &state.db.recovery, "recovery_users"),
+    ];
+    let _ = writeln!(out, "# HELP stardust_credentials Stored credentials by kind.");
+    let _ = writeln!(out, "# TYPE stardust_credentials gauge");
+    for (kind, pool, table) in credentials {
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(pool)
+            .await?;
+        let _ = writeln!(out, "stardust_credentials{{kind=\"{}\"}} {}", kind, count);
+    }
+
+    let outbox: Vec<(String, i64)> =
+        sqlx::query_as("SELECT status, COUNT(*) FROM mail_outbox GROUP BY status")
+            .fetch_all(&state.db.mail)
+            .await?;
+    let _ = writeln!(out, "# HELP stardust_mail_outbox Queued outbound emails by status.");
+    let _ = writeln!(out, "# TYPE stardust_mail_outbox gauge");
+    for status in ["pending", "sent", "failed", "expired"] {
+        let count = outbox
+            .iter()
+            .find(|(s, _)| s == status)
+            .map_or(0, |(_, count)| *count);
+        let _ = writeln!(out, "stardust_mail_outbox{{status=\"{}\"}} {}", status, count);
+    }
+
+    let mut response = out.into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    Ok(response)
+}
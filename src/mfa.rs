@@ -0,0 +1,550 @@
+// src/mfa.rs
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    email,
+    error::{AppError, FieldError},
+    extract::AppJson,
+    logging::{log, LogLevel},
+    passkey::{self, Assertion, StoredCredential},
+    password::{self, StoredPassword},
+    rate_limiting::ClientIp,
+    response, session,
+    users::{self, UserRow},
+    AppState,
+};
+use axum::{
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use sqlx::SqlitePool;
+use std::{env, net::IpAddr, sync::OnceLock};
+
+/// Seconds a sign-in waits for its second factor, unless `MFA_CHALLENGE_TTL_SECS` is set.
+const DEFAULT_CHALLENGE_TTL_SECS: i64 = 300;
+/// Wrong answers to one challenge before it is spent. Each also counts
+/// towards the account lock, like a wrong password.
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// TOTP parameters (RFC 6238) used by common authenticator apps.
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Steps either side of the current one that are accepted, for clock drift.
+const TOTP_SKEW: i64 = 1;
+
+/// A second factor a sign-in can be completed with.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Factor {
+    Totp,
+    Passkey,
+    Recovery,
+}
+
+impl Factor {
+    fn as_str(self) -> &'static str {
+        match self {
+            Factor::Totp => "totp",
+            Factor::Passkey => "passkey",
+            Factor::Recovery => "recovery",
+        }
+    }
+}
+
+fn challenge_ttl_secs() -> i64 {
+    env::var("MFA_CHALLENGE_TTL_SECS")
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_CHALLENGE_TTL_SECS)
+}
+
+/// The lowest user level that must sign in with a second factor, from
+/// `MFA_REQUIRED_LEVEL`, e.g. `100` for admins and owners (see `rbac::Role`).
+/// Unset, a second factor is only asked of users who have one.
+///
+/// Stardust has no route for enrolling a second factor, so a user without one
+/// could never sign in again. The level is therefore only enforced when
+/// `MFA_ENROLLMENT_EXTERNAL=1` confirms that factors are provisioned by
+/// another system writing to the factor databases, and is otherwise ignored
+/// with a warning. Read once, at startup.
+pub fn required_level() -> Option<i64> {
+    static REQUIRED_LEVEL: OnceLock<Option<i64>> = OnceLock::new();
+    *REQUIRED_LEVEL.get_or_init(|| {
+        let level = env::var("MFA_REQUIRED_LEVEL")
+            .ok()
+            .and_then(|val| val.trim().parse::<i64>().ok())?;
+        if env::var("MFA_ENROLLMENT_EXTERNAL").is_ok_and(|val| val == "1") {
+            log(
+                LogLevel::Info,
+                &format!("Requiring a second factor from user level {}", level),
+            );
+            return Some(level);
+        }
+        log(
+            LogLevel::Warn,
+            "Ignoring MFA_REQUIRED_LEVEL: second factors cannot be enrolled here. \
+            Set MFA_ENROLLMENT_EXTERNAL=1 if they are provisioned elsewhere.",
+        );
+        None
+    })
+}
+
+/// Whether users at `user_level` must sign in with a second factor.
+pub fn required_for(user_level: i64) -> bool {
+    required_level().is_some_and(|min| user_level >= min)
+}
+
+/// The second factors a user can sign in with. A recovery code is a backup,
+/// offered only alongside TOTP or a passkey, and passkeys count only while a
+/// relying party is configured.
+pub async fn enrolled_factors(state: &AppState, user_id: &str) -> Result<Vec<Factor>, AppError> {
+    let db = &state.db;
+    let mut factors = Vec::new();
+    if users::has_factor(&db.totp, "totp_users", "totp_secret", user_id).await? {
+        factors.push(Factor::Totp);
+    }
+    if passkey::relying_party().is_some()
+        && users::has_factor(&db.passkey, "passkey_users", "public_key", user_id).await?
+    {
+        factors.push(Factor::Passkey);
+    }
+    if !factors.is_empty()
+        && users::has_factor(&db.recovery, "recovery_users", "recovery_key", user_id).await?
+    {
+        factors.push(Factor::Recovery);
+    }
+    Ok(factors)
+}
+
+/// Refuses to sign in a user whose level requires a second factor they have
+/// not set up.
+pub fn enrollment_required() -> AppError {
+    AppError::Forbidden {
+        code: "mfa.enrollment_required",
+        message: "This account must sign in with a second factor, but has none set up.".to_string(),
+    }
+}
+
+/// Starts the second step of a sign-in whose first factor checked out. The
+/// response carries the token to answer with, the factors on offer and, if a
+/// passkey is one, the options for `navigator.credentials.get()`.
+pub async fn start_challenge(
+    state: &AppState,
+    user: &UserRow,
+    factors: &[Factor],
+    ip: Option<IpAddr>,
+) -> Result<Response, AppError> {
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at <= datetime('now')")
+        .execute(&state.db.session)
+        .await?;
+
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let ttl = challenge_ttl_secs();
+
+    let mut passkey_challenge = None;
+    let mut passkey_options = None;
+    if factors.contains(&Factor::Passkey)
+        && let Some(rp) = passkey::relying_party()
+    {
+        let credential_ids: Vec<String> = sqlx::query_scalar(
+            "SELECT credential_id FROM passkey_users
+            WHERE user_id = ? AND public_key IS NOT NULL AND credential_id IS NOT NULL",
+        )
+        .bind(&user.user_id)
+        .fetch_all(&state.db.passkey)
+        .await?;
+        let challenge = passkey::new_challenge();
+        passkey_options = Some(passkey::request_options(
+            &rp,
+            &challenge,
+            &credential_ids,
+            ttl,
+            false,
+        ));
+        passkey_challenge = Some(challenge);
+    }
+
+    let expires_at: String = sqlx::query_scalar(
+        "INSERT INTO mfa_challenges (token_hash, user_id, factors, passkey_challenge, expires_at, ip)
+        VALUES (?, ?, ?, ?, datetime('now', '+' || ? || ' seconds'), ?)
+        RETURNING expires_at",
+    )
+    .bind(email::hash_token(&token))
+    .bind(&user.user_id)
+    .bind(factors.iter().map(|f| f.as_str()).collect::<Vec<_>>().join(","))
+    .bind(&passkey_challenge)
+    .bind(ttl)
+    .bind(ip.map(|ip| ip.to_string()))
+    .fetch_one(&state.db.session)
+    .await?;
+
+    Ok(response::success(Some(json!({
+        "mfa_required": true,
+        "challenge_token": token,
+        "factors": factors,
+        "expires_at": expires_at,
+        "passkey": passkey_options,
+    }))))
+}
+
+/// Decodes an RFC 4648 base32 TOTP secret, ignoring case, spaces and padding.
+fn decode_base32(secret: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    let mut out = Vec::new();
+    for c in secret.bytes().filter(|c| !matches!(c, b' ' | b'=')) {
+        let value = ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    (!out.is_empty()).then_some(out)
+}
+
+/// The TOTP code for time step `step` (RFC 6238 with HMAC-SHA1).
+fn totp_code(secret: &[u8], step: i64) -> Option<u32> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).ok()?;
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = (u32::from(digest[offset] & 0x7f) << 24)
+        | (u32::from(digest[offset + 1]) << 16)
+        | (u32::from(digest[offset + 2]) << 8)
+        | u32::from(digest[offset + 3]);
+    Some(value % 10u32.pow(TOTP_DIGITS))
+}
+
+/// Checks a TOTP code and claims its time step, so each code is accepted
+/// only once.
+async fn verify_totp(db: &SqlitePool, user_id: &str, code: &str) -> Result<bool, AppError> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(false);
+    }
+    let Ok(code) = code.parse::<u32>() else {
+        return Ok(false);
+    };
+
+    let row: Option<(String, Option<i64>)> = sqlx::query_as(
+        "SELECT totp_secret, last_used_step FROM totp_users WHERE user_id = ? AND totp_secret IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+    let Some((secret, last_used_step)) = row else {
+        return Ok(false);
+    };
+    let Some(secret) = decode_base32(&secret) else {
+        log(LogLevel::Warn, &format!("TOTP secret of user {} is not valid base32", user_id));
+        return Ok(false);
+    };
+
+    let now = Utc::now().timestamp() / TOTP_STEP_SECS;
+    let Some(step) = (now - TOTP_SKEW..=now + TOTP_SKEW).find(|&step| totp_code(&secret, step) == Some(code))
+    else {
+        return Ok(false);
+    };
+    if last_used_step.is_some_and(|last| step <= last) {
+        return Ok(false);
+    }
+    // Claims the step, unless a concurrent request got there first.
+    let claimed = sqlx::query(
+        "UPDATE totp_users SET last_used_step = ?
+        WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)",
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(db)
+    .await?;
+    Ok(claimed.rows_affected() > 0)
+}
+
+/// Checks a recovery code against the stored hash and spends it.
+async fn use_recovery_code(
+    state: &AppState,
+    user_id: &str,
+    code: &str,
+    ip: Option<IpAddr>,
+) -> Result<bool, AppError> {
+    let stored: Option<String> = sqlx::query_scalar(
+        "SELECT recovery_key FROM recovery_users WHERE user_id = ? AND recovery_key IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db.recovery)
+    .await?;
+    let Some(stored) = stored else {
+        return Ok(false);
+    };
+    let verification = password::verify(
+        code.trim().to_string(),
+        Some(StoredPassword {
+            password_hash: stored.clone(),
+            salt: None,
+        }),
+    )
+    .await?;
+    if !verification.matches {
+        return Ok(false);
+    }
+
+    let spent = sqlx::query(
+        "UPDATE recovery_users SET recovery_key = NULL WHERE user_id = ? AND recovery_key = ?",
+    )
+    .bind(user_id)
+    .bind(&stored)
+    .execute(&state.db.recovery)
+    .await?;
+    if spent.rows_affected() == 0 {
+        return Ok(false);
+    }
+    state.audit.spawn_record(
+        AuditEvent::new(user_id, "mfa.recovery_used", Outcome::Success)
+            .target_user(user_id)
+            .ip(ip),
+    );
+    Ok(true)
+}
+
+/// Checks a passkey assertion against the challenge's WebAuthn challenge and
+/// records the authenticator's new signature counter.
+async fn verify_passkey(
+    state: &AppState,
+    user_id: &str,
+    challenge: Option<&str>,
+    assertion: &Assertion,
+) -> Result<bool, AppError> {
+    let (Some(rp), Some(challenge)) = (passkey::relying_party(), challenge) else {
+        return Ok(false);
+    };
+    let stored: Option<StoredCredential> = sqlx::query_as(
+        "SELECT credential_id, public_key, sign_count FROM passkey_users
+        WHERE user_id = ? AND public_key IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db.passkey)
+    .await?;
+    let Some(stored) = stored else {
+        return Ok(false);
+    };
+    match passkey::verify_assertion(&rp, challenge, &stored, assertion, false) {
+        Ok(sign_count) => passkey::record_sign_count(&state.db.passkey, user_id, sign_count).await,
+        Err(e) => {
+            log(LogLevel::Debug, &format!("Passkey assertion rejected: {:#}", e));
+            Ok(false)
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompleteChallenge {
+    challenge_token: String,
+    factor: Factor,
+    /// A TOTP or recovery code.
+    code: Option<String>,
+    /// A passkey assertion.
+    credential: Option<Assertion>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Challenge {
+    user_id: String,
+    factors: String,
+    passkey_challenge: Option<String>,
+}
+
+/// The answer to a challenge, checked against the factor it names.
+enum Answer<'a> {
+    Totp(&'a str),
+    Recovery(&'a str),
+    Passkey(&'a Assertion),
+}
+
+fn challenge_invalid() -> AppError {
+    AppError::BadRequest {
+        code: "mfa.challenge_invalid",
+        message: "The sign-in challenge is invalid or has expired. Sign in again.".to_string(),
+    }
+}
+
+fn missing(field: &str, message: &str) -> AppError {
+    AppError::Validation(vec![FieldError::new(field, "required", message)])
+}
+
+/// `POST /v1/sessions/mfa`
+///
+/// Completes a sign-in that `POST /v1/sessions` answered with `mfa_required`,
+/// using one of the factors it offered. Wrong answers count towards the
+/// account lock, and after too many the challenge is spent.
+pub async fn complete(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<CompleteChallenge>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let answer = match body.factor {
+        Factor::Totp => Answer::Totp(
+            body.code
+                .as_deref()
+                .ok_or_else(|| missing("code", "Enter the code from your authenticator app."))?,
+        ),
+        Factor::Recovery => Answer::Recovery(
+            body.code
+                .as_deref()
+                .ok_or_else(|| missing("code", "Enter a recovery code."))?,
+        ),
+        Factor::Passkey => Answer::Passkey(
+            body.credential
+                .as_ref()
+                .ok_or_else(|| missing("credential", "Expected a passkey assertion."))?,
+        ),
+    };
+
+    // Claims an attempt before the answer is checked, so concurrent guesses
+    // cannot all be checked against the same remaining count.
+    let token_hash = email::hash_token(body.challenge_token.trim());
+    let challenge: Option<Challenge> = sqlx::query_as(
+        "UPDATE mfa_challenges SET attempts = attempts + 1
+        WHERE token_hash = ? AND expires_at > datetime('now') AND attempts < ?
+        RETURNING user_id, factors, passkey_challenge",
+    )
+    .bind(&token_hash)
+    .bind(MAX_CHALLENGE_ATTEMPTS)
+    .fetch_optional(&state.db.session)
+    .await?;
+    let Some(challenge) = challenge else {
+        return Err(challenge_invalid());
+    };
+    let user = match users::fetch_user(&state.db, &challenge.user_id).await {
+        Ok(user) => user,
+        Err(AppError::NotFound { .. }) => return Err(challenge_invalid()),
+        Err(e) => return Err(e),
+    };
+    if !challenge.factors.split(',').any(|f| f == body.factor.as_str()) {
+        return Err(AppError::BadRequest {
+            code: "mfa.factor_unavailable",
+            message: format!(
+                "Factor {} is not available for this sign-in.",
+                body.factor.as_str()
+            ),
+        });
+    }
+
+    let accepted = match answer {
+        Answer::Totp(code) => verify_totp(&state.db.totp, &user.user_id, code).await?,
+        Answer::Recovery(code) => use_recovery_code(&state, &user.user_id, code, ip).await?,
+        Answer::Passkey(assertion) => {
+            verify_passkey(
+                &state,
+                &user.user_id,
+                challenge.passkey_challenge.as_deref(),
+                assertion,
+            )
+            .await?
+        }
+    };
+
+    if !accepted {
+        session::count_failed_login(&state, &user, ip).await?;
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "auth.mfa", Outcome::Failure)
+                .target_user(&user.user_id)
+                .ip(ip),
+        );
+        state.metrics.record_auth_failure(&AppError::SecondFactorInvalid);
+        return Err(AppError::SecondFactorInvalid);
+    }
+
+    // Spends the challenge, unless a concurrent request got there first.
+    let spent = sqlx::query("DELETE FROM mfa_challenges WHERE token_hash = ?")
+        .bind(&token_hash)
+        .execute(&state.db.session)
+        .await?;
+    if spent.rows_affected() == 0 {
+        return Err(challenge_invalid());
+    }
+
+    // The account may have been suspended or locked since the first step.
+    let user = users::fetch_user(&state.db, &user.user_id).await?;
+    session::ensure_can_sign_in(&state, &user, ip)?;
+    session::finish_login(&state, &user, ip, &headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+
+    /// The RFC 6238 SHA-1 secret, "12345678901234567890", in base32.
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn base32_decodes_rfc_4648_vectors() {
+        assert_eq!(decode_base32(RFC_SECRET).unwrap(), b"12345678901234567890");
+        assert_eq!(decode_base32("mzxw6ytb oi======").unwrap(), b"foobar");
+        assert!(decode_base32("not base32!").is_none());
+        assert!(decode_base32("").is_none());
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        let secret = decode_base32(RFC_SECRET).unwrap();
+        // The RFC lists 8-digit codes; the last six digits are the 6-digit code.
+        for (time, expected) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+            (20_000_000_000, 353_130),
+        ] {
+            assert_eq!(totp_code(&secret, time / TOTP_STEP_SECS), Some(expected), "T={}", time);
+        }
+    }
+
+    #[tokio::test]
+    async fn totp_codes_cannot_be_replayed() {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        let dir = env::temp_dir().join(format!("stardust-mfa-{}", hex::encode(unique)));
+        let db = sqlite::initialize_totp_db(&dir).await.unwrap();
+        sqlx::query("INSERT INTO totp_users (user_id, totp_secret) VALUES ('u1', ?)")
+            .bind(RFC_SECRET)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let secret = decode_base32(RFC_SECRET).unwrap();
+        let now = Utc::now().timestamp() / TOTP_STEP_SECS;
+        let code = |step: i64| format!("{:06}", totp_code(&secret, step).unwrap());
+
+        assert!(!verify_totp(&db, "u1", "12345").await.unwrap());
+        assert!(verify_totp(&db, "u1", &code(now)).await.unwrap());
+        // The same code again, and an older one still within the skew.
+        assert!(!verify_totp(&db, "u1", &code(now)).await.unwrap());
+        assert!(!verify_totp(&db, "u1", &code(now - 1)).await.unwrap());
+        // A later step is still accepted once.
+        assert!(verify_totp(&db, "u1", &code(now + 1)).await.unwrap());
+        assert!(!verify_totp(&db, "u2", &code(now)).await.unwrap());
+
+        db.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
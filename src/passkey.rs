@@ -0,0 +1,540 @@
+// src/passkey.rs
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    error::AppError,
+    extract::AppJson,
+    logging::{log, LogLevel},
+    rate_limiting::ClientIp,
+    response, session,
+    users, AppState,
+};
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use p256::{
+    ecdsa::{signature::Verifier, Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{env, net::IpAddr};
+
+/// Authenticator data flags: the user was present, and verified with a PIN
+/// or biometric.
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+
+/// Seconds a usernameless sign-in challenge stays valid.
+const LOGIN_CHALLENGE_TTL_SECS: i64 = 300;
+/// Unexpired usernameless sign-in challenges one address may hold. Each is a
+/// stored row, so without a cap one client could fill the session database.
+const MAX_LOGIN_CHALLENGES_PER_IP: i64 = 20;
+/// Unexpired usernameless sign-in challenges across all addresses, for
+/// clients that spread their requests over many.
+const MAX_LOGIN_CHALLENGES: i64 = 10_000;
+
+/// The WebAuthn relying party: the domain passkeys are bound to and the
+/// origins allowed to use them.
+pub struct RelyingParty {
+    pub id: String,
+    pub origins: Vec<String>,
+}
+
+/// Reads the relying party from `WEBAUTHN_RP_ID` and `WEBAUTHN_ORIGINS`. The
+/// origins fall back to `PUBLIC_URL` or `VITE_GATEWAY`, and the ID to the host
+/// of the first origin. Without any of these, passkeys are unavailable.
+pub fn relying_party() -> Option<RelyingParty> {
+    let origins: Vec<String> = env::var("WEBAUTHN_ORIGINS")
+        .or_else(|_| env::var("PUBLIC_URL"))
+        .or_else(|_| env::var("VITE_GATEWAY"))
+        .ok()?
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/').to_string())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    let id = match env::var("WEBAUTHN_RP_ID") {
+        Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+        _ => {
+            let origin = origins.first()?;
+            let host = origin.split_once("://").map_or(origin.as_str(), |(_, rest)| rest);
+            host.split([':', '/']).next()?.to_string()
+        }
+    };
+    (!id.is_empty() && !origins.is_empty()).then_some(RelyingParty { id, origins })
+}
+
+/// Decodes a base64url field, tolerating padding.
+fn decode(value: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(URL_SAFE_NO_PAD.decode(value.trim().trim_end_matches('='))?)
+}
+
+/// A random WebAuthn challenge, base64url-encoded.
+pub fn new_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// `PublicKeyCredentialRequestOptions` for `navigator.credentials.get()`, in
+/// the JSON form accepted by `PublicKeyCredential.parseRequestOptionsFromJSON()`.
+/// An empty `credential_ids` lets the browser offer any discoverable passkey.
+pub fn request_options(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential_ids: &[String],
+    timeout_secs: i64,
+    require_user_verification: bool,
+) -> Value {
+    let allow: Vec<Value> = credential_ids
+        .iter()
+        .map(|id| json!({ "type": "public-key", "id": id }))
+        .collect();
+    json!({
+        "challenge": challenge,
+        "rpId": rp.id,
+        "timeout": timeout_secs * 1000,
+        "userVerification": if require_user_verification { "required" } else { "preferred" },
+        "allowCredentials": allow,
+    })
+}
+
+/// A passkey as stored in `passkey_users`.
+#[derive(Debug, sqlx::FromRow)]
+pub struct StoredCredential {
+    pub credential_id: Option<String>,
+    /// Base64url DER `SubjectPublicKeyInfo` of a P-256 key, as returned by
+    /// `AuthenticatorAttestationResponse.getPublicKey()`.
+    pub public_key: String,
+    pub sign_count: i64,
+}
+
+/// The `PublicKeyCredential` returned by `navigator.credentials.get()`, as
+/// serialised by its `toJSON()`.
+#[derive(Deserialize, Debug)]
+pub struct Assertion {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    /// The `user.id` the passkey was registered with: our user ID as UTF-8.
+    /// Always present for discoverable credentials.
+    pub user_handle: Option<String>,
+}
+
+/// Checks an assertion against the challenge it answers and the stored
+/// credential, following the WebAuthn verification steps for ES256 keys.
+/// Returns the authenticator's new signature counter.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential: &StoredCredential,
+    assertion: &Assertion,
+    require_user_verification: bool,
+) -> anyhow::Result<u32> {
+    if credential.credential_id.as_deref() != Some(assertion.id.as_str()) {
+        bail!("Credential ID does not match");
+    }
+
+    let client_data_json = decode(&assertion.response.client_data_json)?;
+    let client_data: Value = serde_json::from_slice(&client_data_json)?;
+    if client_data["type"] != "webauthn.get" {
+        bail!("Unexpected client data type");
+    }
+    if client_data["challenge"] != challenge {
+        bail!("Challenge does not match");
+    }
+    let origin = client_data["origin"].as_str().unwrap_or_default();
+    if !rp.origins.iter().any(|allowed| allowed == origin) {
+        bail!("Origin {} is not allowed", origin);
+    }
+
+    let authenticator_data = decode(&assertion.response.authenticator_data)?;
+    if authenticator_data.len() < 37 {
+        bail!("Authenticator data is too short");
+    }
+    if authenticator_data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        bail!("RP ID hash does not match");
+    }
+    if authenticator_data[32] & FLAG_USER_PRESENT == 0 {
+        bail!("User presence flag is not set");
+    }
+    if require_user_verification && authenticator_data[32] & FLAG_USER_VERIFIED == 0 {
+        bail!("User verification flag is not set");
+    }
+    let sign_count = u32::from_be_bytes(authenticator_data[33..37].try_into()?);
+
+    let public_key = decode(&credential.public_key)?;
+    let key = VerifyingKey::from_public_key_der(&public_key)
+        .map_err(|e| anyhow!("Unsupported public key: {}", e))?;
+    let signature = Signature::from_der(&decode(&assertion.response.signature)?)?;
+    let mut signed = authenticator_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature)?;
+
+    // A counter that fails to advance suggests a cloned authenticator.
+    // Authenticators that do not keep one always report 0.
+    if (sign_count != 0 || credential.sign_count != 0) && i64::from(sign_count) <= credential.sign_count {
+        bail!("Signature counter did not increase");
+    }
+    Ok(sign_count)
+}
+
+/// Stores the counter from a verified assertion. Fails, returning `false`,
+/// if a concurrent assertion already stored the same or a higher counter, so
+/// the counter never moves backwards. Counterless authenticators always
+/// report 0.
+pub async fn record_sign_count(db: &SqlitePool, user_id: &str, sign_count: u32) -> Result<bool, AppError> {
+    let sign_count = i64::from(sign_count);
+    let updated = sqlx::query(
+        "UPDATE passkey_users SET sign_count = ?
+        WHERE user_id = ? AND (sign_count < ? OR (? = 0 AND sign_count = 0))",
+    )
+    .bind(sign_count)
+    .bind(user_id)
+    .bind(sign_count)
+    .bind(sign_count)
+    .execute(db)
+    .await?;
+    Ok(updated.rows_affected() > 0)
+}
+
+/// The challenge an assertion claims to answer, from its client data.
+fn claimed_challenge(assertion: &Assertion) -> Option<String> {
+    let client_data: Value =
+        serde_json::from_slice(&decode(&assertion.response.client_data_json).ok()?).ok()?;
+    client_data["challenge"].as_str().map(str::to_string)
+}
+
+fn unavailable() -> AppError {
+    AppError::Unavailable {
+        code: "passkey.unavailable",
+        message: "Passkey sign-in is not configured.".to_string(),
+        details: None,
+    }
+}
+
+/// Records a rejected passkey sign-in. Failures are not counted towards the
+/// account lock: they take no secret to produce, so counting them would let
+/// anyone lock an account.
+fn rejected(state: &AppState, user_id: Option<&str>, ip: Option<IpAddr>) -> AppError {
+    let mut event = AuditEvent::new("anonymous", "auth.login", Outcome::Failure).ip(ip);
+    if let Some(user_id) = user_id {
+        event = event.target_user(user_id);
+    }
+    state.audit.spawn_record(event);
+    state.metrics.record_auth_failure(&AppError::PasskeyInvalid);
+    AppError::PasskeyInvalid
+}
+
+/// `POST /v1/sessions/passkey/options`
+///
+/// Starts a usernameless sign-in, returning options for
+/// `navigator.credentials.get()` without `allowCredentials`, so the browser
+/// offers whichever passkeys it holds for this site.
+pub async fn login_options(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Result<Response, AppError> {
+    let rp = relying_party().ok_or_else(unavailable)?;
+    sqlx::query("DELETE FROM passkey_challenges WHERE expires_at <= datetime('now')")
+        .execute(&state.db.session)
+        .await?;
+
+    // Counts and inserts in one statement, so concurrent requests cannot all
+    // pass the caps.
+    let challenge = new_challenge();
+    let ip = client_ip.map(|ip| ip.0 .0.to_string());
+    let inserted = sqlx::query(
+        "INSERT INTO passkey_challenges (challenge, expires_at, ip)
+        SELECT ?, datetime('now', '+' || ? || ' seconds'), ?
+        WHERE (SELECT COUNT(*) FROM passkey_challenges WHERE ip IS ?) < ?
+            AND (SELECT COUNT(*) FROM passkey_challenges) < ?",
+    )
+    .bind(&challenge)
+    .bind(LOGIN_CHALLENGE_TTL_SECS)
+    .bind(&ip)
+    .bind(&ip)
+    .bind(MAX_LOGIN_CHALLENGES_PER_IP)
+    .bind(MAX_LOGIN_CHALLENGES)
+    .execute(&state.db.session)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Err(AppError::Throttled {
+            code: "passkey.throttled",
+            message: "Too many passkey sign-ins started. Try again later.".to_string(),
+            retry_after: LOGIN_CHALLENGE_TTL_SECS as u64,
+        });
+    }
+
+    Ok(response::success(Some(request_options(
+        &rp,
+        &challenge,
+        &[],
+        LOGIN_CHALLENGE_TTL_SECS,
+        true,
+    ))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasskeyLogin {
+    credential: Assertion,
+}
+
+/// `POST /v1/sessions/passkey/verify`
+///
+/// Signs in with the assertion answering a challenge from
+/// `POST /v1/sessions/passkey/options`, identifying the user by its
+/// `userHandle`. The passkey must have verified the user, so it stands in
+/// for both factors and no password or MFA challenge follows.
+pub async fn login_verify(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<PasskeyLogin>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let rp = relying_party().ok_or_else(unavailable)?;
+    let assertion = &body.credential;
+
+    // Spends the challenge first, so an assertion is only ever tried once.
+    let Some(challenge) = claimed_challenge(assertion) else {
+        return Err(rejected(&state, None, ip));
+    };
+    let spent = sqlx::query(
+        "DELETE FROM passkey_challenges WHERE challenge = ? AND expires_at > datetime('now')",
+    )
+    .bind(&challenge)
+    .execute(&state.db.session)
+    .await?;
+    if spent.rows_affected() == 0 {
+        return Err(rejected(&state, None, ip));
+    }
+
+    let Some(user_id) = assertion
+        .response
+        .user_handle
+        .as_deref()
+        .and_then(|handle| decode(handle).ok())
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+    else {
+        return Err(rejected(&state, None, ip));
+    };
+    let stored: Option<StoredCredential> = sqlx::query_as(
+        "SELECT credential_id, public_key, sign_count FROM passkey_users
+        WHERE user_id = ? AND public_key IS NOT NULL",
+    )
+    .bind(&user_id)
+    .fetch_optional(&state.db.passkey)
+    .await?;
+    let user = match users::fetch_user(&state.db, &user_id).await {
+        Ok(user) => Some(user),
+        Err(AppError::NotFound { .. }) => None,
+        Err(e) => return Err(e),
+    };
+    let (Some(stored), Some(user)) = (stored, user) else {
+        return Err(rejected(&state, None, ip));
+    };
+
+    let sign_count = match verify_assertion(&rp, &challenge, &stored, assertion, true) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            log(LogLevel::Debug, &format!("Passkey sign-in rejected: {:#}", e));
+            return Err(rejected(&state, Some(&user.user_id), ip));
+        }
+    };
+    if !record_sign_count(&state.db.passkey, &user.user_id, sign_count).await? {
+        log(LogLevel::Debug, "Passkey sign-in rejected: signature counter went backwards");
+        return Err(rejected(&state, Some(&user.user_id), ip));
+    }
+
+    session::ensure_can_sign_in(&state, &user, ip)?;
+    session::finish_login(&state, &user, ip, &headers).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite;
+    use p256::{
+        ecdsa::{signature::Signer, SigningKey},
+        pkcs8::EncodePublicKey,
+    };
+
+    const ORIGIN: &str = "https://id.example.com";
+    const CHALLENGE: &str = "c2lnbi1pbi1jaGFsbGVuZ2U";
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "example.com".to_string(),
+            origins: vec![ORIGIN.to_string()],
+        }
+    }
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32].into()).unwrap()
+    }
+
+    fn credential(sign_count: i64) -> StoredCredential {
+        let der = signing_key().verifying_key().to_public_key_der().unwrap();
+        StoredCredential {
+            credential_id: Some("cred-1".to_string()),
+            public_key: URL_SAFE_NO_PAD.encode(der.as_bytes()),
+            sign_count,
+        }
+    }
+
+    /// What an authenticator puts in an assertion, before it is signed.
+    struct Signed {
+        kind: &'static str,
+        challenge: &'static str,
+        origin: &'static str,
+        rp_id: &'static str,
+        flags: u8,
+        sign_count: u32,
+    }
+
+    impl Default for Signed {
+        fn default() -> Self {
+            Self {
+                kind: "webauthn.get",
+                challenge: CHALLENGE,
+                origin: ORIGIN,
+                rp_id: "example.com",
+                flags: FLAG_USER_PRESENT | FLAG_USER_VERIFIED,
+                sign_count: 5,
+            }
+        }
+    }
+
+    fn assertion(signed: Signed) -> Assertion {
+        let client_data = serde_json::to_vec(&json!({
+            "type": signed.kind,
+            "challenge": signed.challenge,
+            "origin": signed.origin,
+        }))
+        .unwrap();
+        let mut authenticator_data = Sha256::digest(signed.rp_id.as_bytes()).to_vec();
+        authenticator_data.push(signed.flags);
+        authenticator_data.extend_from_slice(&signed.sign_count.to_be_bytes());
+
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: Signature = signing_key().sign(&message);
+        Assertion {
+            id: "cred-1".to_string(),
+            response: AssertionResponse {
+                client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+                authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+                signature: URL_SAFE_NO_PAD.encode(signature.to_der().as_bytes()),
+                user_handle: None,
+            },
+        }
+    }
+
+    fn verify(signed: Signed, stored_count: i64) -> anyhow::Result<u32> {
+        verify_assertion(&rp(), CHALLENGE, &credential(stored_count), &assertion(signed), true)
+    }
+
+    fn rejection(signed: Signed, stored_count: i64) -> String {
+        verify(signed, stored_count).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_a_valid_assertion() {
+        assert_eq!(verify(Signed::default(), 4).unwrap(), 5);
+        // Counterless authenticators report 0 every time.
+        assert_eq!(verify(Signed { sign_count: 0, ..Default::default() }, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_another_credential() {
+        let mut other = assertion(Signed::default());
+        other.id = "cred-2".to_string();
+        let result = verify_assertion(&rp(), CHALLENGE, &credential(0), &other, true);
+        assert_eq!(result.unwrap_err().to_string(), "Credential ID does not match");
+
+        let unbound = StoredCredential {
+            credential_id: None,
+            ..credential(0)
+        };
+        let result = verify_assertion(&rp(), CHALLENGE, &unbound, &assertion(Signed::default()), true);
+        assert_eq!(result.unwrap_err().to_string(), "Credential ID does not match");
+    }
+
+    #[test]
+    fn rejects_wrong_client_data() {
+        let created = Signed { kind: "webauthn.create", ..Default::default() };
+        assert_eq!(rejection(created, 0), "Unexpected client data type");
+        let replayed = Signed { challenge: "b2xkLWNoYWxsZW5nZQ", ..Default::default() };
+        assert_eq!(rejection(replayed, 0), "Challenge does not match");
+        let phished = Signed { origin: "https://id.example.net", ..Default::default() };
+        assert!(rejection(phished, 0).starts_with("Origin "));
+    }
+
+    #[test]
+    fn rejects_wrong_authenticator_data() {
+        let other_rp = Signed { rp_id: "example.net", ..Default::default() };
+        assert_eq!(rejection(other_rp, 0), "RP ID hash does not match");
+        let absent = Signed { flags: FLAG_USER_VERIFIED, ..Default::default() };
+        assert_eq!(rejection(absent, 0), "User presence flag is not set");
+        let unverified = Signed { flags: FLAG_USER_PRESENT, ..Default::default() };
+        assert_eq!(rejection(unverified, 0), "User verification flag is not set");
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let mut tampered = assertion(Signed::default());
+        // Signed with sign_count 5, presented with 6.
+        let mut data = decode(&tampered.response.authenticator_data).unwrap();
+        data[36] = 6;
+        tampered.response.authenticator_data = URL_SAFE_NO_PAD.encode(data);
+        assert!(verify_assertion(&rp(), CHALLENGE, &credential(0), &tampered, true).is_err());
+    }
+
+    #[test]
+    fn rejects_a_counter_that_does_not_increase() {
+        assert_eq!(rejection(Signed::default(), 5), "Signature counter did not increase");
+        assert_eq!(rejection(Signed::default(), 9), "Signature counter did not increase");
+        let reset = Signed { sign_count: 0, ..Default::default() };
+        assert_eq!(rejection(reset, 5), "Signature counter did not increase");
+    }
+
+    #[tokio::test]
+    async fn stored_counter_never_moves_backwards() {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        let dir = env::temp_dir().join(format!("stardust-passkey-{}", hex::encode(unique)));
+        let db = sqlite::initialize_passkey_db(&dir).await.unwrap();
+        sqlx::query("INSERT INTO passkey_users (user_id, public_key, sign_count) VALUES ('u1', 'key', 0)")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // Counterless, until the first non-zero count.
+        assert!(record_sign_count(&db, "u1", 0).await.unwrap());
+        assert!(record_sign_count(&db, "u1", 7).await.unwrap());
+        // A slower concurrent assertion that passed with an older count.
+        assert!(!record_sign_count(&db, "u1", 6).await.unwrap());
+        assert!(!record_sign_count(&db, "u1", 7).await.unwrap());
+        assert!(!record_sign_count(&db, "u1", 0).await.unwrap());
+        assert!(record_sign_count(&db, "u1", 8).await.unwrap());
+
+        db.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
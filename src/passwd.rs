@@ -1,47 +1,57 @@
 // src/passwd.rs
 
-use crate::{response, AppState};
+use crate::{
+    audit::{AuditEvent, Outcome},
+    error::AppError,
+    logging::{log, LogLevel},
+    rate_limiting::ClientIp,
+    rbac::{perm, Authorized},
+    response, AppState,
+};
+use anyhow::{anyhow, Context};
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Extension, State},
     response::Response,
 };
-use fancy_log::{log, LogLevel};
 use serde_json::json;
 use std::{fs, path::Path};
 
 /// Handles token reload requests.
 /// This endpoint allows clients to request the server to reload its API token from the passwd file.
-pub async fn token_reload(State(state): State<AppState>) -> Response {
+/// Every attempt is recorded in the audit log.
+pub async fn token_reload(
+    State(state): State<AppState>,
+    auth: Authorized<perm::TokenReload>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Result<Response, AppError> {
+    let result = reload_token(&state);
+    let outcome = if result.is_ok() {
+        Outcome::Success
+    } else {
+        Outcome::Failure
+    };
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "token.reload", outcome).ip(client_ip.map(|ip| ip.0 .0)),
+    );
+    result
+}
+
+fn reload_token(state: &AppState) -> Result<Response, AppError> {
     let passwd_file = Path::new("/opt/stardust/etc/passwd");
 
     // Check if passwd file exists
     if !passwd_file.exists() {
-        log(LogLevel::Error, "Passwd file not found during token reload request.");
-        return response::error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Token file not found.",
-        );
+        return Err(anyhow!("Passwd file not found during token reload request.").into());
     }
 
     // Read the current token from file
-    let file_token = match fs::read_to_string(&passwd_file) {
-        Ok(content) => content.trim().to_string(),
-        Err(e) => {
-            log(LogLevel::Error, &format!("Failed to read passwd file: {}", e));
-            return response::error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to read token file.",
-            );
-        }
-    };
+    let file_token = fs::read_to_string(passwd_file)
+        .context("Failed to read passwd file")?
+        .trim()
+        .to_string();
 
     if file_token.is_empty() {
-        log(LogLevel::Error, "Passwd file is empty during token reload.");
-        return response::error(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Token file is empty.",
-        );
+        return Err(anyhow!("Passwd file is empty during token reload.").into());
     }
 
     // Compare with current token in memory
@@ -53,10 +63,10 @@ pub async fn token_reload(State(state): State<AppState>) -> Response {
     if file_token == current_token {
         // Token hasn't changed
         log(LogLevel::Debug, "Token reload requested, but token hasn't changed.");
-        response::success(Some(json!({
+        Ok(response::success(Some(json!({
             "message": "Token unchanged",
             "reloaded": false
-        })))
+        }))))
     } else {
         // Token has changed, update the application state
         {
@@ -66,9 +76,9 @@ pub async fn token_reload(State(state): State<AppState>) -> Response {
 
         log(LogLevel::Info, "Token reload requested and token has been updated successfully.");
 
-        response::success(Some(json!({
+        Ok(response::success(Some(json!({
             "message": "Token reloaded successfully",
             "reloaded": true
-        })))
+        }))))
     }
 }
@@ -0,0 +1,272 @@
+// src/password.rs
+
+use crate::logging::{log, LogLevel};
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{env, sync::OnceLock};
+use subtle::ConstantTimeEq;
+
+/// Hashes a password with Argon2id and returns it as a PHC string,
+/// which embeds the parameters and salt.
+fn hash(password: &str) -> anyhow::Result<String> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!("Failed to encode salt: {}", e))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Hashes a new password on the blocking pool, for storing in `passwd_users`.
+pub async fn hash_new(password: String) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || hash(&password)).await?
+}
+
+/// A hash of a random password, verified against when the account is unknown
+/// or has no password so that every sign-in attempt costs the same.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let mut password = [0u8; 32];
+        rand::rng().fill_bytes(&mut password);
+        hash(&hex::encode(password)).unwrap_or_default()
+    })
+}
+
+/// The hash formats found in `passwd_users`. Anything but Argon2id with the
+/// current parameters is rehashed on the next successful sign-in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    /// `$argon2id$...` PHC string, the current format.
+    Argon2,
+    /// `$2a$`, `$2b$` or `$2y$` modular crypt string.
+    Bcrypt,
+    /// `$scrypt$...` PHC string.
+    Scrypt,
+    /// `$pbkdf2-sha256$...` PHC string.
+    Pbkdf2,
+    /// `pbkdf2_sha256$<iterations>$<salt>$<base64 hash>`, as written by Django.
+    Pbkdf2Django,
+    /// Hex SHA-256 of the password and the `salt` column, concatenated in the
+    /// order set by `LEGACY_SHA256_ORDER` (`salt_password`, the default, or
+    /// `password_salt`).
+    SaltedSha256,
+}
+
+impl Scheme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::Argon2 => "argon2id",
+            Scheme::Bcrypt => "bcrypt",
+            Scheme::Scrypt => "scrypt",
+            Scheme::Pbkdf2 => "pbkdf2-sha256",
+            Scheme::Pbkdf2Django => "pbkdf2_sha256",
+            Scheme::SaltedSha256 => "salted-sha256",
+        }
+    }
+}
+
+/// Recognises the scheme of a stored hash, or `None` if it is not one we verify.
+pub fn identify(hash: &str, salt: Option<&str>) -> Option<Scheme> {
+    if ["$2a$", "$2b$", "$2y$"].iter().any(|p| hash.starts_with(p)) {
+        return hash.parse::<bcrypt::HashParts>().ok().map(|_| Scheme::Bcrypt);
+    }
+    if hash.starts_with("pbkdf2_sha256$") {
+        return (hash.split('$').count() == 4).then_some(Scheme::Pbkdf2Django);
+    }
+    if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return salt.is_some().then_some(Scheme::SaltedSha256);
+    }
+    match PasswordHash::new(hash).ok()?.algorithm.as_str() {
+        "argon2id" => Some(Scheme::Argon2),
+        "scrypt" => Some(Scheme::Scrypt),
+        "pbkdf2-sha256" => Some(Scheme::Pbkdf2),
+        _ => None,
+    }
+}
+
+/// Highest PBKDF2 iteration count accepted in an imported hash.
+const MAX_IMPORT_PBKDF2_ITERATIONS: u32 = 1_200_000;
+/// Highest scrypt parameters accepted in an imported hash: `ln` (log2 of N),
+/// `r` and `p`. The limits allow 128 MiB of memory per verification.
+const MAX_IMPORT_SCRYPT: (u32, u32, u32) = (17, 8, 4);
+/// Highest bcrypt cost (log2 of the rounds) accepted in an imported hash.
+const MAX_IMPORT_BCRYPT_COST: u32 = 14;
+/// Highest Argon2 parameters accepted in an imported hash: `m` (KiB of
+/// memory), `t` and `p`.
+const MAX_IMPORT_ARGON2: (u32, u32, u32) = (65536, 10, 4);
+
+/// Whether an imported hash is cheap enough to verify on sign-in. Its cost
+/// parameters are chosen by whoever made it, and an unbounded count would let
+/// one sign-in attempt tie up a blocking thread for minutes.
+pub fn import_cost_acceptable(scheme: Scheme, hash: &str) -> bool {
+    let params = || PasswordHash::new(hash).ok().map(|parsed| parsed.params);
+    match scheme {
+        Scheme::Pbkdf2Django => hash
+            .split('$')
+            .nth(1)
+            .and_then(|iterations| iterations.parse::<u32>().ok())
+            .is_some_and(|iterations| iterations <= MAX_IMPORT_PBKDF2_ITERATIONS),
+        Scheme::Pbkdf2 => params()
+            .and_then(|params| params.get_decimal("i"))
+            .is_some_and(|iterations| iterations <= MAX_IMPORT_PBKDF2_ITERATIONS),
+        Scheme::Scrypt => params().is_some_and(|params| {
+            let (max_ln, max_r, max_p) = MAX_IMPORT_SCRYPT;
+            [("ln", max_ln), ("r", max_r), ("p", max_p)]
+                .into_iter()
+                .all(|(name, max)| params.get_decimal(name).is_some_and(|value| value <= max))
+        }),
+        Scheme::Argon2 => params().is_some_and(|params| {
+            let (max_m, max_t, max_p) = MAX_IMPORT_ARGON2;
+            [("m", max_m), ("t", max_t), ("p", max_p)]
+                .into_iter()
+                .all(|(name, max)| params.get_decimal(name).is_some_and(|value| value <= max))
+        }),
+        Scheme::Bcrypt => hash
+            .parse::<bcrypt::HashParts>()
+            .is_ok_and(|parts| parts.get_cost() <= MAX_IMPORT_BCRYPT_COST),
+        Scheme::SaltedSha256 => true,
+    }
+}
+
+/// Whether an Argon2 hash was made with other parameters than we use now.
+fn argon2_outdated(parsed: &PasswordHash) -> bool {
+    let current = Argon2::default();
+    let Ok(params) = Params::try_from(parsed) else {
+        return true;
+    };
+    parsed.algorithm != Algorithm::Argon2id.ident()
+        || parsed.version != Some(Version::default().into())
+        || params.m_cost() != current.params().m_cost()
+        || params.t_cost() != current.params().t_cost()
+        || params.p_cost() != current.params().p_cost()
+}
+
+fn verify_django(password: &str, hash: &str) -> anyhow::Result<bool> {
+    let mut parts = hash.split('$').skip(1);
+    let (Some(iterations), Some(salt), Some(expected)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!("Malformed pbkdf2_sha256 hash"));
+    };
+    let iterations: u32 = iterations.parse()?;
+    let expected = STANDARD.decode(expected)?;
+    let mut derived = vec![0u8; expected.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), iterations, &mut derived);
+    Ok(derived.ct_eq(&expected).into())
+}
+
+fn verify_salted_sha256(password: &str, hash: &str, salt: &str) -> anyhow::Result<bool> {
+    let mut hasher = Sha256::new();
+    if env::var("LEGACY_SHA256_ORDER").is_ok_and(|order| order == "password_salt") {
+        hasher.update(password.as_bytes());
+        hasher.update(salt.as_bytes());
+    } else {
+        hasher.update(salt.as_bytes());
+        hasher.update(password.as_bytes());
+    }
+    let expected = hex::decode(hash)?;
+    Ok(hasher.finalize().as_slice().ct_eq(&expected).into())
+}
+
+/// Checks `password` against a stored hash of a known scheme.
+fn verify_scheme(scheme: Scheme, password: &str, hash: &str, salt: Option<&str>) -> anyhow::Result<bool> {
+    let phc = || PasswordHash::new(hash).map_err(|e| anyhow!("Malformed password hash: {}", e));
+    Ok(match scheme {
+        Scheme::Argon2 => Argon2::default().verify_password(password.as_bytes(), &phc()?).is_ok(),
+        Scheme::Scrypt => scrypt::Scrypt.verify_password(password.as_bytes(), &phc()?).is_ok(),
+        Scheme::Pbkdf2 => pbkdf2::Pbkdf2.verify_password(password.as_bytes(), &phc()?).is_ok(),
+        Scheme::Bcrypt => bcrypt::verify(password, hash)?,
+        Scheme::Pbkdf2Django => verify_django(password, hash)?,
+        Scheme::SaltedSha256 => verify_salted_sha256(password, hash, salt.unwrap_or_default())?,
+    })
+}
+
+/// A row of `passwd_users`.
+#[derive(Debug, sqlx::FromRow)]
+pub struct StoredPassword {
+    pub password_hash: String,
+    pub salt: Option<String>,
+}
+
+/// The result of checking a password.
+pub struct Verification {
+    pub matches: bool,
+    /// The password matched a legacy or outdated hash, which should be
+    /// replaced with `hash_new`.
+    pub needs_rehash: bool,
+}
+
+/// Checks `password` against a stored hash in any supported scheme. Runs on
+/// the blocking pool, since password hashes are deliberately slow. Unknown
+/// accounts and unrecognised hashes are checked against a dummy hash, so
+/// they take as long as a real mismatch.
+pub async fn verify(password: String, stored: Option<StoredPassword>) -> anyhow::Result<Verification> {
+    tokio::task::spawn_blocking(move || {
+        let scheme = stored
+            .as_ref()
+            .and_then(|s| identify(&s.password_hash, s.salt.as_deref()));
+        let (Some(stored), Some(scheme)) = (stored, scheme) else {
+            let _ = verify_scheme(Scheme::Argon2, &password, dummy_hash(), None);
+            return Ok(Verification {
+                matches: false,
+                needs_rehash: false,
+            });
+        };
+
+        let matches = verify_scheme(scheme, &password, &stored.password_hash, stored.salt.as_deref())
+            .unwrap_or_else(|e| {
+                log(LogLevel::Warn, &format!("Failed to verify {} hash: {:#}", scheme.as_str(), e));
+                false
+            });
+        // A mismatch against a fast legacy hash would return sooner than one
+        // against Argon2, revealing which accounts still have one.
+        if !matches && scheme != Scheme::Argon2 {
+            let _ = verify_scheme(Scheme::Argon2, &password, dummy_hash(), None);
+        }
+        let needs_rehash = matches
+            && match scheme {
+                Scheme::Argon2 => PasswordHash::new(&stored.password_hash)
+                    .map_or(true, |parsed| argon2_outdated(&parsed)),
+                _ => true,
+            };
+        Ok(Verification {
+            matches,
+            needs_rehash,
+        })
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_cost_is_capped() {
+        let django = |iterations: u32| format!("pbkdf2_sha256${}$salt$aGFzaA==", iterations);
+        assert!(import_cost_acceptable(Scheme::Pbkdf2Django, &django(870_000)));
+        assert!(!import_cost_acceptable(Scheme::Pbkdf2Django, &django(50_000_000)));
+
+        let phc = |id: &str, params: &str| format!("${}${}$c2FsdHNhbHQ$aGFzaGhhc2hoYXNo", id, params);
+        assert!(import_cost_acceptable(Scheme::Pbkdf2, &phc("pbkdf2-sha256", "i=600000")));
+        assert!(!import_cost_acceptable(Scheme::Pbkdf2, &phc("pbkdf2-sha256", "i=4000000000")));
+        assert!(import_cost_acceptable(Scheme::Scrypt, &phc("scrypt", "ln=15,r=8,p=1")));
+        assert!(!import_cost_acceptable(Scheme::Scrypt, &phc("scrypt", "ln=24,r=8,p=1")));
+        assert!(!import_cost_acceptable(Scheme::Scrypt, &phc("scrypt", "ln=15,r=64,p=1")));
+        assert!(import_cost_acceptable(Scheme::Argon2, &phc("argon2id", "v=19,m=19456,t=2,p=1")));
+        assert!(!import_cost_acceptable(Scheme::Argon2, &phc("argon2id", "v=19,m=4194304,t=2,p=1")));
+        assert!(!import_cost_acceptable(Scheme::Argon2, &phc("argon2id", "v=19,m=19456,t=1000,p=1")));
+        assert!(!import_cost_acceptable(Scheme::Argon2, &phc("argon2id", "v=19,m=19456,t=2,p=64")));
+
+        let bcrypt = |cost: u32| format!("$2b${:02}$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW", cost);
+        assert!(import_cost_acceptable(Scheme::Bcrypt, &bcrypt(12)));
+        assert!(!import_cost_acceptable(Scheme::Bcrypt, &bcrypt(31)));
+    }
+}
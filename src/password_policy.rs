@@ -0,0 +1,434 @@
+// src/password_policy.rs
+
+use crate::{
+    error::{AppError, FieldError},
+    logging::{log, LogLevel},
+    sqlite::Databases,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::OnceLock,
+};
+
+const DEFAULT_MIN_LEN: usize = 8;
+const DEFAULT_MAX_LEN: usize = 256;
+/// Lowest accepted strength score (0-4), unless `PASSWORD_MIN_SCORE` is set.
+const DEFAULT_MIN_SCORE: u8 = 2;
+
+/// Directory of breached-password range files, unless `PASSWORD_BREACH_DIR`
+/// is set. Laid out like the Have I Been Pwned range API: one file per
+/// 5-character SHA-1 prefix, named `{PREFIX}.txt`, with `{SUFFIX}:{COUNT}`
+/// lines. Only the prefix file is read, so the corpus never sees a full hash.
+const DEFAULT_BREACH_DIR: &str = "/opt/stardust/etc/pwned-passwords";
+
+/// Common passwords and words, most common first. A match costs its rank in
+/// guesses, so these contribute almost nothing to a password's strength.
+const COMMON: &str = "\
+password 123456 12345678 qwerty 123456789 12345 1234 111111 1234567 dragon
+123123 baseball abc123 football monkey letmein shadow master 696969 mustang
+666666 qwertyuiop 123321 1234567890 sunflower superman 654321 1qaz2wsx 7777777
+abcdef qazwsx jordan jennifer 123qwe 121212 killer trustno1 hunter harley
+zxcvbnm asdfgh buster batman soccer tigger charlie robert hockey ranger
+daniel starwars klaster george computer michelle jessica pepper 112233
+zxcvbn freedom princess maggie pass ginger summer ashley love iloveyou
+welcome secret sunshine flower hello admin login passw0rd access hottie
+loveme whatever nicole thomas michael andrew matthew joshua cheese amanda
+orange yankees silver golden diamond winter spring autumn november october
+chelsea arsenal liverpool family friends forever angel baby cookie purple
+chocolate banana apple rainbow butterfly samsung google internet changeme
+default guest root administrator system stardust user test qwerty123
+";
+
+/// Keyboard rows, for spotting runs like `asdf` or `poiuy`.
+const KEYBOARD_ROWS: [&str; 4] = ["qwertyuiop", "asdfghjkl", "zxcvbnm", "1234567890"];
+
+/// Rules a new password must meet.
+pub struct PasswordPolicy {
+    min_len: usize,
+    max_len: usize,
+    min_score: u8,
+    breach_dir: Option<PathBuf>,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let env_num = |name: &str| env::var(name).ok().and_then(|v| v.trim().parse::<usize>().ok());
+        let min_len = env_num("PASSWORD_MIN_LEN").filter(|&v| v > 0).unwrap_or(DEFAULT_MIN_LEN);
+        let max_len = env_num("PASSWORD_MAX_LEN")
+            .filter(|&v| v >= min_len)
+            .unwrap_or(DEFAULT_MAX_LEN.max(min_len));
+        let min_score = env_num("PASSWORD_MIN_SCORE")
+            .map_or(DEFAULT_MIN_SCORE, |v| v.min(4) as u8);
+
+        let dir = PathBuf::from(
+            env::var("PASSWORD_BREACH_DIR").unwrap_or_else(|_| DEFAULT_BREACH_DIR.to_string()),
+        );
+        let breach_dir = if dir.is_dir() {
+            log(
+                LogLevel::Info,
+                &format!("Checking new passwords against breach corpus in {}", dir.display()),
+            );
+            Some(dir)
+        } else {
+            log(
+                LogLevel::Warn,
+                &format!(
+                    "No breached-password corpus at {}; passwords are not checked against it.",
+                    dir.display()
+                ),
+            );
+            None
+        };
+
+        Self {
+            min_len,
+            max_len,
+            min_score,
+            breach_dir,
+        }
+    }
+
+    /// Checks a new password, given personal details it must not contain
+    /// (emails, handle). Returns every rule it breaks, for `field`.
+    pub async fn check(
+        &self,
+        field: &str,
+        password: &str,
+        user_inputs: &[String],
+    ) -> Result<(), AppError> {
+        let len = password.chars().count();
+        if len < self.min_len {
+            return Err(AppError::Validation(vec![FieldError::new(
+                field,
+                "too_short",
+                format!("Must be at least {} characters.", self.min_len),
+            )]));
+        }
+        if len > self.max_len {
+            return Err(AppError::Validation(vec![FieldError::new(
+                field,
+                "too_long",
+                format!("Must be at most {} characters.", self.max_len),
+            )]));
+        }
+
+        let mut errors = Vec::new();
+        let lower = password.to_lowercase();
+        if user_inputs
+            .iter()
+            .any(|input| input.chars().count() >= 3 && lower.contains(&input.to_lowercase()))
+        {
+            errors.push(FieldError::new(
+                field,
+                "contains_personal_info",
+                "Must not contain your email address or handle.",
+            ));
+        }
+        if strength_score(password, user_inputs) < self.min_score {
+            errors.push(FieldError::new(
+                field,
+                "too_weak",
+                "Too easy to guess. Use a longer password and avoid common words, \
+                repeated characters, sequences and keyboard patterns.",
+            ));
+        }
+        if self.is_breached(password).await? {
+            errors.push(FieldError::new(
+                field,
+                "breached",
+                "This password has appeared in a data breach. Choose a different one.",
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(errors))
+        }
+    }
+
+    /// Looks the password up in the local breach corpus by SHA-1 prefix.
+    /// Entries with a count of 0 are padding and do not count.
+    async fn is_breached(&self, password: &str) -> Result<bool, AppError> {
+        let Some(dir) = &self.breach_dir else {
+            return Ok(false);
+        };
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(5);
+        let path = dir.join(format!("{}.txt", prefix));
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(anyhow::Error::from(e).context("Failed to read breach corpus").into()),
+        };
+        Ok(contents.lines().any(|line| {
+            line.split_once(':').is_some_and(|(s, count)| {
+                s.trim().eq_ignore_ascii_case(suffix) && count.trim().parse::<u64>().is_ok_and(|c| c > 0)
+            })
+        }))
+    }
+}
+
+/// Personal details a user's password must not contain: their email
+/// addresses, the local parts of those, and their handle.
+pub async fn user_inputs(db: &Databases, user_id: &str) -> Result<Vec<String>, AppError> {
+    let emails: Vec<String> = sqlx::query_scalar("SELECT email FROM email_users WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_all(&db.email)
+        .await?;
+    let handles: Vec<String> = sqlx::query_scalar("SELECT handle FROM handle_users WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_all(&db.handle)
+        .await?;
+
+    let mut inputs = Vec::new();
+    for email in emails {
+        if let Some((local, _)) = email.split_once('@') {
+            inputs.push(local.split('+').next().unwrap_or(local).to_string());
+        }
+        inputs.push(email);
+    }
+    inputs.extend(handles);
+    Ok(inputs)
+}
+
+fn common_ranks() -> &'static HashMap<&'static str, u32> {
+    static RANKS: OnceLock<HashMap<&'static str, u32>> = OnceLock::new();
+    RANKS.get_or_init(|| {
+        COMMON
+            .split_whitespace()
+            .enumerate()
+            .map(|(i, word)| (word, i as u32 + 1))
+            .collect()
+    })
+}
+
+/// Undoes common character substitutions (`p4ssw0rd`).
+fn unleet(c: char) -> char {
+    match c {
+        '0' => 'o',
+        '1' | '!' => 'i',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+/// log10 of the guesses needed for `chars[i..j]` if it forms a recognisable
+/// pattern, or `None`.
+fn pattern_guesses(chars: &[char], lower: &[char], i: usize, j: usize, inputs: &[Vec<char>]) -> Option<f64> {
+    let len = j - i;
+    let token = &lower[i..j];
+
+    if inputs.iter().any(|input| input.as_slice() == token) {
+        return Some(0.0);
+    }
+
+    let word: String = token.iter().collect();
+    let unleeted: String = token.iter().map(|&c| unleet(c)).collect();
+    let rank = common_ranks()
+        .get(word.as_str())
+        .map(|&rank| (rank, false))
+        .or_else(|| common_ranks().get(unleeted.as_str()).map(|&rank| (rank, true)));
+    if let Some((rank, leet)) = rank {
+        let mut guesses = (rank as f64).log10();
+        if chars[i..j].iter().any(|c| c.is_uppercase()) {
+            guesses += 2f64.log10();
+        }
+        if leet {
+            guesses += 2f64.log10();
+        }
+        return Some(guesses);
+    }
+
+    if len >= 3 && token.iter().all(|&c| c == token[0]) {
+        return Some((10.0 * len as f64).log10());
+    }
+
+    if len >= 3 {
+        let step = token[1] as i64 - token[0] as i64;
+        if (step == 1 || step == -1)
+            && token.windows(2).all(|w| w[1] as i64 - w[0] as i64 == step)
+        {
+            return Some((10.0 * len as f64).log10());
+        }
+        let run: String = token.iter().collect();
+        let reversed: String = token.iter().rev().collect();
+        if KEYBOARD_ROWS
+            .iter()
+            .any(|row| row.contains(run.as_str()) || row.contains(reversed.as_str()))
+        {
+            return Some((20.0 * len as f64).log10());
+        }
+    }
+
+    if len == 4 {
+        let year: String = token.iter().collect();
+        if year.parse::<u32>().is_ok_and(|y| (1900..=2099).contains(&y)) {
+            return Some(120f64.log10());
+        }
+    }
+    None
+}
+
+/// Estimates how hard a password is to guess, on zxcvbn's 0-4 scale.
+///
+/// Like zxcvbn, the password is split into the sequence of segments that is
+/// cheapest to guess: common words, personal details, repeats, sequences,
+/// keyboard runs and years, with any other character costing 10 guesses.
+/// The score follows from the total: under 10^3 guesses is 0, 10^6 is 1,
+/// 10^8 is 2, 10^10 is 3, and anything more is 4.
+pub fn strength_score(password: &str, user_inputs: &[String]) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = password.to_lowercase().chars().collect();
+    if lower.len() != chars.len() {
+        // Case mapping changed the length; score the lowercase form alone.
+        return strength_score(&password.to_lowercase(), user_inputs);
+    }
+    let inputs: Vec<Vec<char>> = user_inputs
+        .iter()
+        .map(|input| input.to_lowercase().chars().collect())
+        .collect();
+
+    let n = chars.len();
+    // best[j]: log10 of the fewest guesses for the first j characters.
+    let mut best = vec![f64::INFINITY; n + 1];
+    best[0] = 0.0;
+    for j in 1..=n {
+        let patterns = (0..j.saturating_sub(1)).filter_map(|i| {
+            pattern_guesses(&chars, &lower, i, j, &inputs).map(|guesses| best[i] + guesses)
+        });
+        best[j] = patterns.fold(best[j - 1] + 1.0, f64::min);
+    }
+
+    match best[n] {
+        g if g < 3.0 => 0,
+        g if g < 6.0 => 1,
+        g if g < 8.0 => 2,
+        g if g < 10.0 => 3,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn score(password: &str) -> u8 {
+        strength_score(password, &[])
+    }
+
+    #[test]
+    fn common_passwords_score_zero() {
+        for password in ["password", "Password", "12345678", "dragon", "iloveyou"] {
+            assert_eq!(score(password), 0, "{}", password);
+        }
+    }
+
+    #[test]
+    fn leet_substitutions_are_undone() {
+        assert_eq!(score("p@ssw0rd"), 0);
+        assert_eq!(score("P@55w0rd"), 0);
+    }
+
+    #[test]
+    fn sequences_and_repeats_score_zero() {
+        for password in ["abcdefgh", "hgfedcba", "98765432", "zzzzzzzzzz"] {
+            assert_eq!(score(password), 0, "{}", password);
+        }
+    }
+
+    #[test]
+    fn keyboard_runs_score_zero() {
+        for password in ["qwertyuiop", "asdfghjkl", "poiuytrewq", "monkeyqwerty"] {
+            assert_eq!(score(password), 0, "{}", password);
+        }
+    }
+
+    #[test]
+    fn words_with_years_stay_weak() {
+        assert_eq!(score("summer2024"), 1);
+        assert_eq!(score("robert1987"), 1);
+        assert_eq!(score("19871987"), 1);
+    }
+
+    #[test]
+    fn personal_info_scores_zero() {
+        assert_eq!(score("alicesmith"), 4);
+        assert_eq!(strength_score("alicesmith", &["alicesmith".to_string()]), 0);
+        assert_eq!(strength_score("AliceSmith", &["alicesmith".to_string()]), 0);
+    }
+
+    #[test]
+    fn long_random_passwords_score_four() {
+        assert_eq!(score("Tr0ub4dor"), 3);
+        assert_eq!(score("x7#Kq9!vLm2@pZ"), 4);
+        assert_eq!(score("correcthorsebatterystaple"), 4);
+    }
+
+    fn policy(breach_dir: Option<PathBuf>) -> PasswordPolicy {
+        PasswordPolicy {
+            min_len: DEFAULT_MIN_LEN,
+            max_len: DEFAULT_MAX_LEN,
+            min_score: DEFAULT_MIN_SCORE,
+            breach_dir,
+        }
+    }
+
+    /// A breach corpus holding `entries` as `(password, count)` pairs.
+    fn breach_corpus(entries: &[(&str, u64)]) -> PathBuf {
+        let mut unique = [0u8; 8];
+        rand::rng().fill_bytes(&mut unique);
+        let dir = env::temp_dir().join(format!("stardust-breach-{}", hex::encode(unique)));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (password, count) in entries {
+            let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+            let (prefix, suffix) = hash.split_at(5);
+            let path = dir.join(format!("{}.txt", prefix));
+            let mut contents = std::fs::read_to_string(&path).unwrap_or_default();
+            contents.push_str(&format!("{}:{}\r\n", suffix, count));
+            std::fs::write(&path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn breach_lookup_reads_the_prefix_file() {
+        let dir = breach_corpus(&[("hunter2", 17_000), ("padding", 0)]);
+        let policy = policy(Some(dir.clone()));
+        assert!(policy.is_breached("hunter2").await.unwrap());
+        // A count of 0 is padding, not a breach.
+        assert!(!policy.is_breached("padding").await.unwrap());
+        // No file for the prefix.
+        assert!(!policy.is_breached("x7#Kq9!vLm2@pZ").await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn breach_lookup_is_skipped_without_a_corpus() {
+        assert!(!policy(None).is_breached("hunter2").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn check_reports_every_broken_rule() {
+        let dir = breach_corpus(&[("alicesmith1", 3)]);
+        let policy = policy(Some(dir.clone()));
+        let Err(AppError::Validation(errors)) = policy
+            .check("password", "alicesmith1", &["alicesmith".to_string()])
+            .await
+        else {
+            panic!("expected validation errors");
+        };
+        let codes: Vec<&str> = errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, ["contains_personal_info", "too_weak", "breached"]);
+
+        assert!(policy.check("password", "x7#Kq9!vLm2@pZ", &[]).await.is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+// src/password_reset.rs
+
+use crate::{
+    account,
+    audit::{AuditEvent, Outcome},
+    email,
+    error::AppError,
+    extract::AppJson,
+    logging::{log, LogLevel},
+    password, password_policy,
+    rate_limiting::ClientIp,
+    response, session,
+    templates::EmailKind,
+    users::{self, UserRow},
+    AppState,
+};
+use axum::{
+    extract::{Extension, State},
+    http::HeaderMap,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use serde::Deserialize;
+use std::{env, net::IpAddr};
+
+/// Seconds a reset token stays valid, unless `PASSWORD_RESET_TTL_SECS` is set.
+const DEFAULT_TTL_SECS: i64 = 1800;
+/// Reset emails per account per window, unless `PASSWORD_RESET_MAX_PER_ACCOUNT` is set.
+const DEFAULT_MAX_PER_ACCOUNT: i64 = 3;
+/// Reset requests, and separately failed confirmations, per IP per window,
+/// unless `PASSWORD_RESET_MAX_PER_IP` is set.
+const DEFAULT_MAX_PER_IP: i64 = 10;
+const WINDOW_SECS: i64 = 3600;
+
+/// Spent and expired tokens are kept this long for the audit trail.
+const RETENTION_SECS: i64 = 24 * 3600;
+
+fn env_i64(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|val| *val > 0)
+        .unwrap_or(default)
+}
+
+/// Seconds until `ip` may make another attempt of `kind`, or 0.
+async fn ip_wait(state: &AppState, kind: &str, ip: Option<IpAddr>) -> Result<i64, AppError> {
+    let max = env_i64("PASSWORD_RESET_MAX_PER_IP", DEFAULT_MAX_PER_IP);
+    // Ages of the most recent `max` attempts in the window, newest first.
+    let ages: Vec<i64> = sqlx::query_scalar(
+        "SELECT CAST(strftime('%s', 'now') AS INTEGER) - CAST(strftime('%s', created_at) AS INTEGER)
+        FROM password_reset_attempts
+        WHERE kind = ? AND ip IS ? AND created_at > datetime('now', '-' || ? || ' seconds')
+        ORDER BY created_at DESC, id DESC
+        LIMIT ?",
+    )
+    .bind(kind)
+    .bind(ip.map(|ip| ip.to_string()))
+    .bind(WINDOW_SECS)
+    .bind(max)
+    .fetch_all(&state.db.passwd)
+    .await?;
+    Ok(match ages.last() {
+        Some(age) if ages.len() as i64 >= max => (WINDOW_SECS - age).max(1),
+        _ => 0,
+    })
+}
+
+fn throttled(retry_after: i64) -> AppError {
+    AppError::Throttled {
+        code: "password_reset.throttled",
+        message: "Too many password reset attempts. Try again later.".to_string(),
+        retry_after: retry_after as u64,
+    }
+}
+
+async fn record_attempt(
+    state: &AppState,
+    kind: &str,
+    ip: Option<IpAddr>,
+    user_id: Option<&str>,
+) -> Result<(), AppError> {
+    sqlx::query("INSERT INTO password_reset_attempts (kind, ip, user_id) VALUES (?, ?, ?)")
+        .bind(kind)
+        .bind(ip.map(|ip| ip.to_string()))
+        .bind(user_id)
+        .execute(&state.db.passwd)
+        .await?;
+    Ok(())
+}
+
+/// Drops attempts that no longer count and tokens past their retention.
+async fn prune(state: &AppState) -> Result<(), AppError> {
+    sqlx::query(
+        "DELETE FROM password_reset_attempts WHERE created_at <= datetime('now', '-' || ? || ' seconds')",
+    )
+    .bind(WINDOW_SECS)
+    .execute(&state.db.passwd)
+    .await?;
+    sqlx::query(
+        "DELETE FROM password_resets WHERE expires_at <= datetime('now', '-' || ? || ' seconds')",
+    )
+    .bind(RETENTION_SECS)
+    .execute(&state.db.passwd)
+    .await?;
+    Ok(())
+}
+
+/// Issues a token for `user`, spending any earlier one, and emails it to
+/// `email`, unless the account has hit its limit for the window.
+async fn issue_reset(
+    state: &AppState,
+    user: &UserRow,
+    email: &str,
+    ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> anyhow::Result<()> {
+    let sent: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM password_reset_attempts
+        WHERE kind = 'request' AND user_id = ? AND created_at > datetime('now', '-' || ? || ' seconds')",
+    )
+    .bind(&user.user_id)
+    .bind(WINDOW_SECS)
+    .fetch_one(&state.db.passwd)
+    .await?;
+    // The attempt being handled is already counted.
+    if sent > env_i64("PASSWORD_RESET_MAX_PER_ACCOUNT", DEFAULT_MAX_PER_ACCOUNT) {
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "password.reset_request", Outcome::Failure)
+                .target_user(&user.user_id)
+                .ip(ip),
+        );
+        return Ok(());
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let ttl = env_i64("PASSWORD_RESET_TTL_SECS", DEFAULT_TTL_SECS);
+
+    let mut tx = state.db.passwd.begin().await?;
+    sqlx::query(
+        "UPDATE password_resets SET consumed_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND consumed_at IS NULL",
+    )
+    .bind(&user.user_id)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO password_resets (token_hash, user_id, expires_at, ip)
+        VALUES (?, ?, datetime('now', '+' || ? || ' seconds'), ?)",
+    )
+    .bind(email::hash_token(&token))
+    .bind(&user.user_id)
+    .bind(ttl)
+    .bind(ip.map(|ip| ip.to_string()))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let locale = state
+        .mailer
+        .templates()
+        .choose_locale(user.locale.as_deref(), Some(headers));
+    let mut context = tera::Context::new();
+    context.insert("token", &token);
+    context.insert("link", &email::public_link("/reset-password", &token));
+    context.insert("expires_minutes", &(ttl / 60));
+    state
+        .mailer
+        .enqueue_template(email, EmailKind::PasswordReset, &locale, context)
+        .await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new("anonymous", "password.reset_request", Outcome::Success)
+            .target_user(&user.user_id)
+            .ip(ip),
+    );
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RequestReset {
+    email: String,
+}
+
+/// `POST /v1/password-reset`
+///
+/// Emails a single-use reset link to a verified address. The response is the
+/// same whether or not the address belongs to an account, and the email is
+/// prepared in the background so timing does not tell either.
+pub async fn request_reset(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<RequestReset>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let email = email::normalize(&body.email).map_err(|e| AppError::Validation(vec![e]))?;
+
+    let wait = ip_wait(&state, "request", ip).await?;
+    if wait > 0 {
+        return Err(throttled(wait));
+    }
+    prune(&state).await?;
+
+    let owner: Option<String> =
+        sqlx::query_scalar("SELECT user_id FROM email_users WHERE email = ? AND verified = 1")
+            .bind(&email)
+            .fetch_optional(&state.db.email)
+            .await?;
+    record_attempt(&state, "request", ip, owner.as_deref()).await?;
+
+    if let Some(user_id) = owner {
+        tokio::spawn(async move {
+            let user = match users::fetch_user(&state.db, &user_id).await {
+                Ok(user) => user,
+                Err(AppError::NotFound { .. }) => return,
+                Err(e) => {
+                    log(LogLevel::Error, &format!("Password reset lookup failed: {}", e));
+                    return;
+                }
+            };
+            if let Err(e) = issue_reset(&state, &user, &email, ip, &headers).await {
+                log(LogLevel::Error, &format!("Failed to issue password reset: {:#}", e));
+            }
+        });
+    }
+
+    Ok(response::success(None))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConfirmReset {
+    token: String,
+    password: String,
+}
+
+/// `POST /v1/password-reset/confirm`
+///
+/// Sets a new password with a reset token, spending it, and signs the user
+/// out everywhere. Failed attempts count towards the caller's IP limit.
+pub async fn confirm_reset(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<ConfirmReset>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let wait = ip_wait(&state, "confirm", ip).await?;
+    if wait > 0 {
+        return Err(throttled(wait));
+    }
+    let invalid = || AppError::BadRequest {
+        code: "password_reset.invalid_token",
+        message: "The reset link is invalid or has expired.".to_string(),
+    };
+    let token_hash = email::hash_token(body.token.trim());
+    let user_id: Option<String> = sqlx::query_scalar(
+        "SELECT user_id FROM password_resets
+        WHERE token_hash = ? AND consumed_at IS NULL AND expires_at > datetime('now')",
+    )
+    .bind(&token_hash)
+    .fetch_optional(&state.db.passwd)
+    .await?;
+    let user = match user_id {
+        Some(user_id) => match users::fetch_user(&state.db, &user_id).await {
+            Ok(user) => Some(user),
+            Err(AppError::NotFound { .. }) => None,
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+    let Some(user) = user else {
+        record_attempt(&state, "confirm", ip, None).await?;
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "password.reset", Outcome::Failure).ip(ip),
+        );
+        return Err(invalid());
+    };
+
+    // Checked before the token is spent, so a rejected password can be retried.
+    let inputs = password_policy::user_inputs(&state.db, &user.user_id).await?;
+    state.passwords.check("password", &body.password, &inputs).await?;
+
+    let hash = password::hash_new(body.password).await?;
+    let mut tx = state.db.passwd.begin().await?;
+    // Spends the token, unless a concurrent request got there first.
+    let spent = sqlx::query(
+        "UPDATE password_resets SET consumed_at = CURRENT_TIMESTAMP
+        WHERE token_hash = ? AND consumed_at IS NULL AND expires_at > datetime('now')",
+    )
+    .bind(&token_hash)
+    .execute(&mut *tx)
+    .await?;
+    if spent.rows_affected() == 0 {
+        return Err(invalid());
+    }
+    sqlx::query(
+        "INSERT INTO passwd_users (user_id, password_hash, salt) VALUES (?, ?, NULL)
+        ON CONFLICT (user_id) DO UPDATE SET password_hash = excluded.password_hash, salt = NULL",
+    )
+    .bind(&user.user_id)
+    .bind(&hash)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "UPDATE password_resets SET consumed_at = CURRENT_TIMESTAMP
+        WHERE user_id = ? AND consumed_at IS NULL",
+    )
+    .bind(&user.user_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    session::revoke_user_sessions(&state.db, &user.user_id).await?;
+    account::clear_failed_logins(&state.db, &user.user_id).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(&user.user_id, "password.reset", Outcome::Success)
+            .target_user(&user.user_id)
+            .ip(ip),
+    );
+
+    Ok(response::success(None))
+}
@@ -1,6 +1,6 @@
 // src/public.rs
 
-use fancy_log::{log, LogLevel};
+use crate::logging::{log, LogLevel};
 use rust_embed::RustEmbed;
 use std::{fs, path::Path};
 
@@ -19,24 +19,18 @@ pub fn setup_public_directory() -> Result<(), Box<dyn std::error::Error>> {
 
     for file_path in PublicAssets::iter() {
         let target_path = public_dir.join(file_path.as_ref());
-        let should_extract = if target_path.exists() {
-            false
-        } else {
-            true
-        };
+        let should_extract = !target_path.exists();
 
-        if should_extract {
-            if let Some(file_data) = PublicAssets::get(&file_path) {
-                if let Some(parent) = target_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-
-                fs::write(&target_path, file_data.data.as_ref())?;
-                log(
-                    LogLevel::Debug,
-                    &format!("Extracted: {}", target_path.display()),
-                );
+        if should_extract && let Some(file_data) = PublicAssets::get(&file_path) {
+            if let Some(parent) = target_path.parent() {
+                fs::create_dir_all(parent)?;
             }
+
+            fs::write(&target_path, file_data.data.as_ref())?;
+            log(
+                LogLevel::Debug,
+                &format!("Extracted: {}", target_path.display()),
+            );
         }
     }
 
@@ -1,22 +1,33 @@
 // src/rate_limiting.rs
 
-use crate::response;
+use crate::{
+    audit::{Audit, AuditEvent, Outcome},
+    error::AppError,
+    logging::{log, LogLevel},
+    metrics::Metrics,
+};
 use axum::{
     body::{Body, Bytes},
     extract::ConnectInfo,
-    http::{Request, Response as AxumResponse, StatusCode},
+    http::{HeaderValue, Request, Response as AxumResponse},
 };
 use futures::future::BoxFuture;
+use axum::response::IntoResponse;
 use http_body::Body as HttpBody;
 use std::{
     collections::HashMap,
+    env,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, OnceLock},
     task::{Context, Poll},
     time::{Duration, Instant},
 };
 use tower::{Layer, Service};
 
+/// The client IP the rate limiter keyed the request on, stored as a request extension.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
 #[derive(Clone)]
 pub struct RateLimiterState {
     store: Arc<Mutex<HashMap<IpAddr, (Instant, u8)>>>,
@@ -42,11 +53,18 @@ impl RateLimiterState {
 #[derive(Clone)]
 pub struct RateLimitLayer {
     state: RateLimiterState,
+    metrics: Metrics,
+    audit: Audit,
 }
 
 impl RateLimitLayer {
-    pub fn new(state: RateLimiterState) -> Self {
-        Self { state }
+    pub fn new(state: RateLimiterState, metrics: Metrics, audit: Audit) -> Self {
+        trusted_proxies();
+        Self {
+            state,
+            metrics,
+            audit,
+        }
     }
 }
 
@@ -56,6 +74,8 @@ impl<S> Layer<S> for RateLimitLayer {
         RateLimitService {
             inner,
             state: self.state.clone(),
+            metrics: self.metrics.clone(),
+            audit: self.audit.clone(),
         }
     }
 }
@@ -64,6 +84,8 @@ impl<S> Layer<S> for RateLimitLayer {
 pub struct RateLimitService<S> {
     inner: S,
     state: RateLimiterState,
+    metrics: Metrics,
+    audit: Audit,
 }
 
 impl<S, B> Service<Request<B>> for RateLimitService<S>
@@ -93,44 +115,210 @@ where
             record.0 = now;
             record.1 = 1;
         } else {
-            record.1 += 1;
+            record.1 = record.1.saturating_add(1);
         }
 
         if record.1 > limit {
+            // Audit only the first rejection per window to keep floods out of the log.
+            if record.1 == limit + 1 {
+                self.audit.spawn_record(
+                    AuditEvent::new("anonymous", "rate_limit.block", Outcome::Failure).ip(Some(ip)),
+                );
+            }
             // Use the standard error response format.
-            let resp = response::error(StatusCode::TOO_MANY_REQUESTS, "Too many requests");
+            let retry_after = window.saturating_sub(now.duration_since(record.0)).as_secs().max(1);
+            self.metrics.record_rate_limit_rejection();
+            let mut resp = AppError::RateLimited.into_response();
+            add_rate_limit_headers(&mut resp, limit, 0);
+            resp.headers_mut()
+                .insert("retry-after", HeaderValue::from(retry_after));
             return Box::pin(async { Ok(resp) });
         }
 
+        let remaining = limit - record.1;
         drop(store);
-        let request = request.map(Body::new);
+        let mut request = request.map(Body::new);
+        request.extensions_mut().insert(ClientIp(ip));
         let future = self.inner.call(request);
         Box::pin(async move {
-            let response = future.await?;
+            let mut response = future.await?;
+            add_rate_limit_headers(&mut response, limit, remaining);
             Ok(response)
         })
     }
 }
 
-fn extract_client_ip<B>(req: &Request<B>) -> Option<IpAddr> {
-    let headers = req.headers();
-    if let Some(header_value) = headers.get("x-forwarded-for") {
-        if let Ok(as_str) = header_value.to_str() {
-            if let Some(client_ip_str) = as_str.split(',').next() {
-                if let Ok(ip) = client_ip_str.trim().parse::<IpAddr>() {
-                    return Some(ip);
-                }
-            }
-        }
+fn add_rate_limit_headers(response: &mut AxumResponse<Body>, limit: u8, remaining: u8) {
+    let headers = response.headers_mut();
+    headers.insert("x-ratelimit-limit", HeaderValue::from(u16::from(limit)));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(u16::from(remaining)));
+}
+
+/// Proxies trusted to report the client address, unless `TRUSTED_PROXIES` is
+/// set: a reverse proxy on the same host.
+const DEFAULT_TRUSTED_PROXIES: &str = "127.0.0.0/8, ::1";
+
+/// An address or CIDR block in `TRUSTED_PROXIES`.
+#[derive(Clone, Copy, Debug)]
+struct ProxyNet {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl ProxyNet {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u32>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
     }
-    if let Some(header_value) = headers.get("x-real-ip") {
-        if let Ok(as_str) = header_value.to_str() {
-            if let Ok(ip) = as_str.trim().parse::<IpAddr>() {
-                return Some(ip);
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
             }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
         }
     }
-    req.extensions()
+}
+
+/// The proxies whose `X-Forwarded-For` and `X-Real-IP` headers are believed,
+/// from the comma-separated addresses and CIDR blocks in `TRUSTED_PROXIES`.
+/// Set it to an empty string to trust no proxy.
+fn trusted_proxies() -> &'static [ProxyNet] {
+    static TRUSTED: OnceLock<Vec<ProxyNet>> = OnceLock::new();
+    TRUSTED.get_or_init(|| {
+        let raw = env::var("TRUSTED_PROXIES").unwrap_or_else(|_| DEFAULT_TRUSTED_PROXIES.to_string());
+        let trusted: Vec<ProxyNet> = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let net = ProxyNet::parse(entry);
+                if net.is_none() {
+                    log(
+                        LogLevel::Warn,
+                        &format!("Ignoring invalid TRUSTED_PROXIES entry: {}", entry),
+                    );
+                }
+                net
+            })
+            .collect();
+        if trusted.is_empty() {
+            log(LogLevel::Info, "Ignoring forwarding headers: no trusted proxies.");
+        } else {
+            log(LogLevel::Info, &format!("Trusting forwarding headers from: {}", raw.trim()));
+        }
+        trusted
+    })
+}
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+    trusted_proxies().iter().any(|net| net.contains(ip))
+}
+
+/// The address of the client that sent the request. Forwarding headers are
+/// only believed when the connection comes from a trusted proxy, and then
+/// the rightmost `X-Forwarded-For` hop that is not itself a trusted proxy is
+/// the client: every hop left of it could have been written by the client.
+pub(crate) fn extract_client_ip<B>(req: &Request<B>) -> Option<IpAddr> {
+    let peer = req
+        .extensions()
         .get::<ConnectInfo<SocketAddr>>()
-        .map(|ci| ci.0.ip())
+        .map(|ci| ci.0.ip().to_canonical())?;
+    if !is_trusted_proxy(peer) {
+        return Some(peer);
+    }
+
+    let headers = req.headers();
+    if headers.contains_key("x-forwarded-for") {
+        // A hop that does not parse leaves nothing trustworthy to its left.
+        let forwarded: Option<Vec<IpAddr>> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|values| {
+                values
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .map(|hop| hop.trim().parse::<IpAddr>().ok().map(|ip| ip.to_canonical()))
+                    .collect()
+            });
+        let Some(forwarded) = forwarded else {
+            return Some(peer);
+        };
+        return forwarded
+            .iter()
+            .rev()
+            .find(|ip| !is_trusted_proxy(**ip))
+            .or(forwarded.first())
+            .copied()
+            .or(Some(peer));
+    }
+    if let Some(header_value) = headers.get("x-real-ip")
+        && let Ok(as_str) = header_value.to_str()
+        && let Ok(ip) = as_str.trim().parse::<IpAddr>()
+    {
+        return Some(ip.to_canonical());
+    }
+    Some(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+        if let Some(value) = forwarded_for {
+            builder = builder.header("x-forwarded-for", value);
+        }
+        let mut req = builder.body(()).unwrap();
+        let peer: SocketAddr = format!("{}:40000", peer).parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    }
+
+    fn client(peer: &str, forwarded_for: Option<&str>) -> String {
+        extract_client_ip(&request(peer, forwarded_for)).unwrap().to_string()
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_forward() {
+        assert_eq!(client("203.0.113.7", Some("198.51.100.1")), "203.0.113.7");
+    }
+
+    #[test]
+    fn trusted_proxy_reports_the_rightmost_untrusted_hop() {
+        assert_eq!(client("127.0.0.1", Some("198.51.100.1")), "198.51.100.1");
+        // The client prepended a hop of its own choosing.
+        assert_eq!(client("127.0.0.1", Some("10.9.9.9, 198.51.100.1")), "198.51.100.1");
+        assert_eq!(client("127.0.0.1", Some("198.51.100.1, 127.0.0.2")), "198.51.100.1");
+        assert_eq!(client("127.0.0.1", Some("garbage, 198.51.100.1")), "127.0.0.1");
+        assert_eq!(client("127.0.0.1", None), "127.0.0.1");
+    }
+
+    #[test]
+    fn proxy_networks_match_by_prefix() {
+        let net = ProxyNet::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let net = ProxyNet::parse("fd00::/8").unwrap();
+        assert!(net.contains("fd12::1".parse().unwrap()));
+        assert!(!net.contains("fe80::1".parse().unwrap()));
+        assert!(ProxyNet::parse("0.0.0.0/0").unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!(ProxyNet::parse("10.0.0.0/33").is_none());
+        assert!(ProxyNet::parse("proxy.local").is_none());
+    }
 }
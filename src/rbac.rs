@@ -0,0 +1,197 @@
+// src/rbac.rs
+
+use crate::{
+    audit::{AuditEvent, Outcome},
+    auth::Caller,
+    error::AppError,
+    rate_limiting::ClientIp,
+    users::UserRow,
+    AppState,
+};
+use axum::{extract::FromRequestParts, http::request::Parts};
+use serde::Serialize;
+use std::{marker::PhantomData, net::IpAddr};
+
+/// Something a caller may be allowed to do.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "users:suspend")]
+    UsersSuspend,
+    #[serde(rename = "users:delete")]
+    UsersDelete,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+    #[serde(rename = "token:reload")]
+    TokenReload,
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::UsersRead => "users:read",
+            Permission::UsersWrite => "users:write",
+            Permission::UsersSuspend => "users:suspend",
+            Permission::UsersDelete => "users:delete",
+            Permission::AuditRead => "audit:read",
+            Permission::TokenReload => "token:reload",
+        }
+    }
+}
+
+/// A named role, derived from `users.user_level`.
+///
+/// | level    | role        |
+/// |----------|-------------|
+/// | 0        | `guest`     |
+/// | 1-49     | `user`      |
+/// | 50-99    | `moderator` |
+/// | 100-254  | `admin`     |
+/// | 255      | `owner`     |
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Guest,
+    User,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn from_level(level: i64) -> Self {
+        match level {
+            ..=0 => Role::Guest,
+            1..=49 => Role::User,
+            50..=99 => Role::Moderator,
+            100..=254 => Role::Admin,
+            _ => Role::Owner,
+        }
+    }
+
+    pub fn permissions(self) -> &'static [Permission] {
+        use Permission::*;
+        match self {
+            Role::Guest | Role::User => &[],
+            Role::Moderator => &[UsersRead, UsersSuspend],
+            Role::Admin => &[UsersRead, UsersWrite, UsersSuspend, UsersDelete, AuditRead],
+            Role::Owner => &[
+                UsersRead,
+                UsersWrite,
+                UsersSuspend,
+                UsersDelete,
+                AuditRead,
+                TokenReload,
+            ],
+        }
+    }
+}
+
+/// Type-level name of a permission, so handlers can require it in their signature.
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+/// Marker types for `Authorized`, one per `Permission`.
+pub mod perm {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! marker {
+        ($name:ident) => {
+            pub struct $name;
+            impl RequiredPermission for $name {
+                const PERMISSION: Permission = Permission::$name;
+            }
+        };
+    }
+
+    marker!(UsersRead);
+    marker!(UsersWrite);
+    marker!(UsersSuspend);
+    marker!(UsersDelete);
+    marker!(AuditRead);
+    marker!(TokenReload);
+}
+
+/// Extracts the authenticated caller, rejecting the request unless they hold
+/// permission `P`, e.g. `Authorized<perm::UsersRead>`.
+///
+/// Only usable on routes behind `auth::auth_middleware`, which identifies the caller.
+pub struct Authorized<P> {
+    pub caller: Caller,
+    permission: PhantomData<P>,
+}
+
+impl<P: RequiredPermission> FromRequestParts<AppState> for Authorized<P> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let caller = parts
+            .extensions
+            .get::<Caller>()
+            .cloned()
+            .ok_or(AppError::AuthHeaderMissing)?;
+        if caller.role().permissions().contains(&P::PERMISSION) {
+            return Ok(Authorized {
+                caller,
+                permission: PhantomData,
+            });
+        }
+
+        let ip = parts.extensions.get::<ClientIp>().map(|ip| ip.0);
+        Err(permission_denied(state, &caller, ip, P::PERMISSION))
+    }
+}
+
+/// Records a missing permission in metrics and the audit log.
+fn permission_denied(
+    state: &AppState,
+    caller: &Caller,
+    ip: Option<IpAddr>,
+    permission: Permission,
+) -> AppError {
+    let error = AppError::Forbidden {
+        code: "auth.permission_denied",
+        message: format!("Missing permission {}.", permission.as_str()),
+    };
+    state.metrics.record_auth_failure(&error);
+    state.audit.spawn_record(
+        AuditEvent::new(caller.actor(), "auth.permission_denied", Outcome::Failure).ip(ip),
+    );
+    error
+}
+
+/// Lets a signed-in user act on their own account. Acting on anyone else
+/// needs `permission` and a higher rank than the target.
+pub fn ensure_self_or(
+    state: &AppState,
+    caller: &Caller,
+    ip: Option<IpAddr>,
+    target: &UserRow,
+    permission: Permission,
+) -> Result<(), AppError> {
+    if let Caller::User(user) = caller
+        && user.user_id == target.user_id
+    {
+        return Ok(());
+    }
+    if !caller.role().permissions().contains(&permission) {
+        return Err(permission_denied(state, caller, ip, permission));
+    }
+    ensure_outranks(caller, target.user_level)
+}
+
+/// Rejects acting on a user ranked at or above a signed-in caller, so that
+/// nobody can manage their peers or grant a level higher than their own.
+pub fn ensure_outranks(caller: &Caller, user_level: i64) -> Result<(), AppError> {
+    match caller {
+        Caller::User(user) if user_level >= user.user_level => Err(AppError::Forbidden {
+            code: "auth.rank_insufficient",
+            message: "Cannot act on a user ranked at or above yourself.".to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
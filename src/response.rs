@@ -1,7 +1,10 @@
 // src/response.rs
 
+use crate::request_id;
 use axum::{
-    http::StatusCode,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Json, Response},
 };
 use chrono::{SecondsFormat, Utc};
@@ -12,13 +15,20 @@ use serde_json::json;
 pub struct PublicSuccessResponse {
     status: String,
     data: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
     timestamp: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PublicErrorResponse {
     status: String,
+    code: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
     timestamp: String,
 }
 
@@ -27,17 +37,116 @@ pub fn success(data: Option<serde_json::Value>) -> Response {
     let response = PublicSuccessResponse {
         status: "Success".to_string(),
         data: data.unwrap_or_else(|| json!({})),
+        request_id: request_id::current(),
         timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
     };
     (StatusCode::OK, Json(response)).into_response()
 }
 
 // 4xx, 5xx
-pub fn error(status: StatusCode, message: impl Into<String>) -> Response {
+// Prefer returning `error::AppError`, which picks the status and code for you.
+pub fn error(
+    status: StatusCode,
+    code: &str,
+    message: impl Into<String>,
+    details: Option<serde_json::Value>,
+) -> Response {
     let response = PublicErrorResponse {
         status: "Error".to_string(),
+        code: code.to_string(),
         message: message.into(),
+        details,
+        request_id: request_id::current(),
         timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
     };
-    (status, Json(response)).into_response()
+    // Keep a copy so `negotiate_error_format` can re-render it as problem details.
+    let mut resp = (status, Json(response.clone())).into_response();
+    resp.extensions_mut().insert(response);
+    resp
+}
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// RFC 9457 problem details, with the envelope's `code`, `details` and
+/// `timestamp` carried as extension members.
+#[derive(Serialize, Debug)]
+struct ProblemDetails {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: String,
+    status: u16,
+    detail: String,
+    instance: String,
+    code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    timestamp: String,
+}
+
+/// Renders error responses as `application/problem+json` when the client's
+/// `Accept` header prefers it over `application/json`. Otherwise the
+/// `PublicErrorResponse` envelope is left untouched.
+pub async fn negotiate_error_format(req: Request, next: Next) -> Response {
+    let wants_problem = prefers_problem_json(req.headers());
+    let instance = req.uri().path().to_string();
+    let mut response = next.run(req).await;
+
+    if !wants_problem {
+        return response;
+    }
+    let Some(error) = response.extensions_mut().remove::<PublicErrorResponse>() else {
+        return response;
+    };
+
+    let status = response.status();
+    let problem = ProblemDetails {
+        problem_type: format!("urn:stardust:error:{}", error.code),
+        title: status.canonical_reason().unwrap_or("Error").to_string(),
+        status: status.as_u16(),
+        detail: error.message,
+        instance,
+        code: error.code,
+        details: error.details,
+        request_id: error.request_id,
+        timestamp: error.timestamp,
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let mut problem_response = Json(problem).into_response();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    *problem_response.headers_mut() = parts.headers;
+    *problem_response.status_mut() = status;
+    problem_response
+}
+
+/// Returns true when `application/problem+json` is explicitly accepted with a
+/// quality at least as high as `application/json`.
+fn prefers_problem_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+
+    let mut problem_q = None;
+    let mut json_q: f32 = 0.0;
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or("").to_ascii_lowercase();
+        let q = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            PROBLEM_JSON => problem_q = Some(q),
+            "application/json" | "application/*" | "*/*" => json_q = json_q.max(q),
+            _ => {}
+        }
+    }
+
+    problem_q.is_some_and(|q| q > 0.0 && q >= json_q)
 }
@@ -1,36 +1,107 @@
 // src/router.rs
 
-use crate::{auth::auth_middleware, passwd, AppState};
+use crate::{
+    account, audit,
+    auth::auth_middleware,
+    cors::{cors_middleware, CorsConfig},
+    email, email_login, handle,
+    health, metrics, mfa, passkey, passwd, password_reset,
+    rate_limiting::RateLimitLayer,
+    session, users, AppState,
+};
 use axum::{
     middleware,
-    routing::post,
+    routing::{get, post, put},
     Router,
 };
+use std::sync::Arc;
+use tower::ServiceBuilder;
 use tower_http::services::ServeDir;
 
 /// Creates the main application router.
-/// API routes are protected by auth middleware.
-/// Static files are served without authentication, including index.html at "/".
-pub fn create_router() -> Router<AppState> {
-    // Create API routes with auth middleware
-    let api_routes = Router::<AppState>::new()
+/// Admin API routes are protected by auth middleware, permission-checked per
+/// handler and never CORS-enabled.
+/// Public API routes use the credentialed SPA CORS policy.
+/// Static files are served without authentication, including index.html at "/",
+/// and allow any origin without credentials.
+/// Everything except the health probes is behind the per-IP rate limiter.
+pub fn create_router(
+    state: AppState,
+    spa_cors: Arc<CorsConfig>,
+    rate_limit: RateLimitLayer,
+) -> Router<AppState> {
+    // Admin routes: the API token or a user session, no CORS headers so browsers
+    // cannot call them cross-origin. Each handler requires its own permission.
+    let admin_routes = Router::<AppState>::new()
         .route("/v1/token/reload", post(passwd::token_reload))
-        .layer(middleware::from_fn_with_state(
-            // We need a dummy state here, will be replaced when with_state is called
-            AppState {
-                api_token: std::sync::Arc::new(std::sync::RwLock::new(String::new())),
-            },
-            auth_middleware,
-        ));
+        .route("/v1/audit/events", get(audit::list_events))
+        .route("/v1/audit/verify", get(audit::verify_chain))
+        .route("/v1/users", get(users::list_users))
+        .route(
+            "/v1/users/{id}",
+            get(users::get_user)
+                .patch(users::update_user)
+                .delete(users::delete_user),
+        )
+        .route("/v1/users/{id}/suspend", post(users::suspend_user))
+        .route("/v1/users/{id}/unsuspend", post(users::unsuspend_user))
+        .route("/v1/users/{id}/password-hash", put(users::import_password_hash))
+        .route("/v1/users/{id}/handle", put(handle::set_handle))
+        .layer(middleware::from_fn_with_state(state, auth_middleware));
 
-    // Combine API routes with static file service (including index.html at "/")
-    Router::<AppState>::new()
-        .merge(api_routes)
-        .fallback_service(
+    // Probes for load balancers: unauthenticated, never CORS-enabled and not
+    // rate limited, since a balancer probes every node from the same address.
+    let health_routes = Router::<AppState>::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+
+    // Public routes called by the SPA, e.g. login endpoints.
+    let public_routes = Router::<AppState>::new()
+        .route("/v1/sessions", post(session::login))
+        .route("/v1/sessions/mfa", post(mfa::complete))
+        .route("/v1/sessions/passkey/options", post(passkey::login_options))
+        .route("/v1/sessions/passkey/verify", post(passkey::login_verify))
+        .route("/v1/sessions/email", post(email_login::request_login))
+        .route("/v1/sessions/email/verify", post(email_login::verify_login))
+        .route(
+            "/v1/sessions/current",
+            get(session::current).delete(session::logout),
+        )
+        .route("/v1/account/locale", put(account::set_locale))
+        .route("/v1/handles/{handle}", get(handle::lookup))
+        .route("/v1/handles/{handle}/availability", get(handle::availability))
+        .route("/v1/password-reset", post(password_reset::request_reset))
+        .route("/v1/password-reset/confirm", post(password_reset::confirm_reset))
+        .route("/v1/emails", post(email::add_email))
+        .route("/v1/emails/resend", post(email::resend_verification))
+        .route("/v1/emails/verify", post(email::verify_email))
+        .layer(middleware::from_fn_with_state(spa_cors, cors_middleware));
+
+    let static_files = ServiceBuilder::new()
+        .layer(middleware::from_fn_with_state(
+            Arc::new(CorsConfig::public()),
+            cors_middleware,
+        ))
+        .service(
             ServeDir::new("/opt/stardust/public")
                 .append_index_html_on_directories(true)
                 .precompressed_gzip()
                 .precompressed_br()
                 .precompressed_deflate(),
-        )
-}
\ No newline at end of file
+        );
+
+    // Combine API routes with static file service (including index.html at "/")
+    Router::<AppState>::new()
+        .merge(admin_routes)
+        .merge(public_routes)
+        .fallback_service(static_files)
+        .layer(rate_limit)
+        .merge(health_routes)
+        .route_layer(middleware::from_fn(metrics::matched_route_middleware))
+}
+
+/// Creates the router for the admin listener, which is bound to a private
+/// address (see `bootstrap::run`) and serves operational endpoints only.
+pub fn create_admin_router() -> Router<AppState> {
+    Router::<AppState>::new().route("/metrics", get(metrics::metrics_handler))
+}
@@ -0,0 +1,483 @@
+// src/session.rs
+
+use crate::{
+    account::{self, AccountStatus},
+    audit::{AuditEvent, Outcome},
+    email,
+    error::AppError,
+    extract::AppJson,
+    handle,
+    logging::{log, LogLevel},
+    mfa,
+    password::{self, StoredPassword},
+    rate_limiting::ClientIp,
+    rbac::Role,
+    response,
+    sqlite::Databases,
+    templates::EmailKind,
+    users::{self, UserRow},
+    AppState,
+};
+use axum::{
+    extract::{Extension, FromRequestParts, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::Utc;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{env, net::IpAddr};
+
+/// Cookie carrying the session token for browser clients.
+pub const SESSION_COOKIE: &str = "stardust_session";
+
+/// Session lifetime in seconds, unless `SESSION_TTL_SECS` is set.
+const DEFAULT_SESSION_TTL_SECS: i64 = 7 * 24 * 3600;
+
+/// Longest `User-Agent` stored with a session.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+fn session_ttl_secs() -> i64 {
+    env::var("SESSION_TTL_SECS")
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_SESSION_TTL_SECS)
+}
+
+/// Sessions are stored under the SHA-256 of their token, so a leaked
+/// database does not hand out usable tokens.
+fn session_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Reads the session token from a `Bearer` authorization header, falling
+/// back to the session cookie.
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    if let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some(token.trim().to_string());
+    }
+    cookie_value(headers, SESSION_COOKIE)
+}
+
+/// Reads the cookie `name` from the request.
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn session_cookie(token: &str, max_age: i64) -> HeaderValue {
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        SESSION_COOKIE, token, max_age
+    );
+    HeaderValue::from_str(&cookie).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+/// A request authenticated by a user session.
+///
+/// Extracting this rejects requests without a live session, and re-checks
+/// the account status on every request so that suspending or locking a user
+/// takes effect immediately.
+#[derive(Clone, Debug)]
+pub struct SessionUser {
+    pub user_id: String,
+    pub user_level: i64,
+    session_id: String,
+}
+
+impl SessionUser {
+    pub fn role(&self) -> Role {
+        Role::from_level(self.user_level)
+    }
+}
+
+impl FromRequestParts<AppState> for SessionUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let token = session_token(&parts.headers).ok_or(AppError::SessionInvalid)?;
+        let session_id = session_id(&token);
+
+        let user_id: String = sqlx::query_scalar(
+            "SELECT user_id FROM sessions WHERE session_id = ? AND expires_at > datetime('now')",
+        )
+        .bind(&session_id)
+        .fetch_optional(&state.db.session)
+        .await?
+        .ok_or(AppError::SessionInvalid)?;
+
+        let user = match users::fetch_user(&state.db, &user_id).await {
+            Ok(user) => user,
+            Err(AppError::NotFound { .. }) => return Err(AppError::SessionInvalid),
+            Err(e) => return Err(e),
+        };
+        account::ensure_can_authenticate(&user)?;
+
+        sqlx::query("UPDATE sessions SET last_seen_at = CURRENT_TIMESTAMP WHERE session_id = ?")
+            .bind(&session_id)
+            .execute(&state.db.session)
+            .await?;
+
+        Ok(SessionUser {
+            user_id: user.user_id,
+            user_level: user.user_level,
+            session_id,
+        })
+    }
+}
+
+/// Revokes every session of a user, and any sign-in waiting for a second
+/// factor. Returns how many sessions were revoked.
+pub async fn revoke_user_sessions(db: &Databases, user_id: &str) -> Result<u64, AppError> {
+    let result = sqlx::query("DELETE FROM sessions WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&db.session)
+        .await?;
+    sqlx::query("DELETE FROM mfa_challenges WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&db.session)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Creates a session for an authenticated user and returns the sign-in
+/// response. The token is only sent as an `HttpOnly` cookie, never in the
+/// body, so scripts on the page cannot read it.
+async fn start_session(
+    state: &AppState,
+    user_id: &str,
+    ip: Option<IpAddr>,
+    user_agent: Option<&str>,
+) -> Result<Response, AppError> {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let ttl = session_ttl_secs();
+
+    sqlx::query("DELETE FROM sessions WHERE user_id = ? AND expires_at <= datetime('now')")
+        .bind(user_id)
+        .execute(&state.db.session)
+        .await?;
+
+    let expires_at: String = sqlx::query_scalar(
+        "INSERT INTO sessions (session_id, user_id, expires_at, ip, user_agent)
+        VALUES (?, ?, datetime('now', '+' || ? || ' seconds'), ?, ?)
+        RETURNING expires_at",
+    )
+    .bind(session_id(&token))
+    .bind(user_id)
+    .bind(ttl)
+    .bind(ip.map(|ip| ip.to_string()))
+    .bind(user_agent.map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect::<String>()))
+    .fetch_one(&state.db.session)
+    .await?;
+
+    let mut response = response::success(Some(json!({
+        "user_id": user_id,
+        "expires_at": expires_at,
+    })));
+    response
+        .headers_mut()
+        .append(header::SET_COOKIE, session_cookie(&token, ttl));
+    Ok(response)
+}
+
+/// Looks up a user by verified email address or, when the identifier has no
+/// `@`, by handle.
+async fn find_user_by_identifier(
+    db: &Databases,
+    identifier: &str,
+) -> Result<Option<UserRow>, AppError> {
+    let user_id: Option<String> = if identifier.contains('@') {
+        let Ok(email) = email::normalize(identifier) else {
+            return Ok(None);
+        };
+        sqlx::query_scalar("SELECT user_id FROM email_users WHERE email = ? AND verified = 1")
+            .bind(email)
+            .fetch_optional(&db.email)
+            .await?
+    } else {
+        sqlx::query_scalar("SELECT user_id FROM handle_users WHERE handle_key = ?")
+            .bind(handle::canonical(identifier))
+            .fetch_optional(&db.handle)
+            .await?
+    };
+    let Some(user_id) = user_id else {
+        return Ok(None);
+    };
+    match users::fetch_user(db, &user_id).await {
+        Ok(user) => Ok(Some(user)),
+        Err(AppError::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordLogin {
+    /// Email address or handle.
+    identifier: String,
+    password: String,
+}
+
+/// `POST /v1/sessions`
+///
+/// Signs in with an email address or handle and a password. Unknown users
+/// and wrong passwords are indistinguishable. The account status is checked
+/// only once the password is correct, so it is never revealed to a caller
+/// who does not know it. Users with a second factor get an MFA challenge
+/// instead of a session, to be answered at `POST /v1/sessions/mfa`.
+pub async fn login(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<PasswordLogin>,
+) -> Result<Response, AppError> {
+    let ip = client_ip.map(|ip| ip.0 .0);
+    let user = find_user_by_identifier(&state.db, body.identifier.trim()).await?;
+    let stored: Option<StoredPassword> = match &user {
+        Some(user) => sqlx::query_as(
+            "SELECT password_hash, salt FROM passwd_users WHERE user_id = ? AND password_hash IS NOT NULL",
+        )
+        .bind(&user.user_id)
+        .fetch_optional(&state.db.passwd)
+        .await?,
+        None => None,
+    };
+    let stored_hash = stored.as_ref().map(|s| s.password_hash.clone());
+
+    let verification = password::verify(body.password.clone(), stored).await?;
+    let verified = verification.matches;
+    let user = match user {
+        Some(user) if verified => user,
+        user => {
+            let mut event = AuditEvent::new("anonymous", "auth.login", Outcome::Failure).ip(ip);
+            if let Some(user) = &user {
+                event = event.target_user(&user.user_id);
+                count_failed_login(&state, user, ip).await?;
+            }
+            state.audit.spawn_record(event);
+            state.metrics.record_auth_failure(&AppError::InvalidCredentials);
+            return Err(AppError::InvalidCredentials);
+        }
+    };
+
+    if verification.needs_rehash
+        && let Some(old_hash) = stored_hash
+        && let Err(e) = upgrade_hash(&state, &user.user_id, &old_hash, body.password).await
+    {
+        log(LogLevel::Warn, &format!("Failed to upgrade password hash: {:#}", e));
+    }
+
+    continue_sign_in(&state, &user, ip, &headers).await
+}
+
+/// Continues a sign-in whose first factor, a password or emailed code,
+/// checked out: starts an MFA challenge if the user has a second factor, and
+/// otherwise signs them in unless their level requires one.
+pub async fn continue_sign_in(
+    state: &AppState,
+    user: &UserRow,
+    ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    ensure_can_sign_in(state, user, ip)?;
+
+    // The failure count is left alone until the second factor is answered,
+    // so a known password does not reset the limit on guessing it.
+    let factors = mfa::enrolled_factors(state, &user.user_id).await?;
+    if !factors.is_empty() {
+        return mfa::start_challenge(state, user, &factors, ip).await;
+    }
+    if mfa::required_for(user.user_level) {
+        let error = mfa::enrollment_required();
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "auth.login", Outcome::Failure)
+                .target_user(&user.user_id)
+                .ip(ip),
+        );
+        state.metrics.record_auth_failure(&error);
+        return Err(error);
+    }
+    finish_login(state, user, ip, headers).await
+}
+
+/// Counts a failed sign-in step against an account, locking it at the limit.
+/// Failures against an account that is already blocked are not counted, so
+/// guessing cannot extend a lock.
+pub async fn count_failed_login(
+    state: &AppState,
+    user: &UserRow,
+    ip: Option<IpAddr>,
+) -> Result<(), AppError> {
+    if account::current_status(user).status == AccountStatus::Active
+        && account::record_failed_login(&state.db, &user.user_id).await?
+    {
+        state.audit.spawn_record(
+            AuditEvent::new("system", "account.lock", Outcome::Success)
+                .target_user(&user.user_id)
+                .ip(ip),
+        );
+    }
+    Ok(())
+}
+
+/// Rejects a sign-in by a user whose account status forbids it, recording
+/// the failure.
+pub fn ensure_can_sign_in(
+    state: &AppState,
+    user: &UserRow,
+    ip: Option<IpAddr>,
+) -> Result<(), AppError> {
+    account::ensure_can_authenticate(user).inspect_err(|error| {
+        state.audit.spawn_record(
+            AuditEvent::new("anonymous", "auth.login", Outcome::Failure)
+                .target_user(&user.user_id)
+                .ip(ip),
+        );
+        state.metrics.record_auth_failure(error);
+    })
+}
+
+/// Completes a sign-in once every required factor checked out: resets the
+/// failure count, sends the new-device alert and starts the session.
+pub async fn finish_login(
+    state: &AppState,
+    user: &UserRow,
+    ip: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    account::clear_failed_logins(&state.db, &user.user_id).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(&user.user_id, "auth.login", Outcome::Success)
+            .target_user(&user.user_id)
+            .ip(ip),
+    );
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    if let Err(e) = notify_new_login(state, user, ip, user_agent, headers).await {
+        log(LogLevel::Warn, &format!("Failed to send sign-in alert: {:#}", e));
+    }
+    start_session(state, &user.user_id, ip, user_agent).await
+}
+
+/// Replaces a legacy or outdated hash with an Argon2id one. Skipped if the
+/// stored hash changed meanwhile, e.g. by a concurrent reset.
+async fn upgrade_hash(
+    state: &AppState,
+    user_id: &str,
+    old_hash: &str,
+    password: String,
+) -> anyhow::Result<()> {
+    let hash = password::hash_new(password).await?;
+    let result = sqlx::query(
+        "UPDATE passwd_users SET password_hash = ?, salt = NULL
+        WHERE user_id = ? AND password_hash = ?",
+    )
+    .bind(&hash)
+    .bind(user_id)
+    .bind(old_hash)
+    .execute(&state.db.passwd)
+    .await?;
+    if result.rows_affected() > 0 {
+        state.audit.spawn_record(
+            AuditEvent::new("system", "password.rehash", Outcome::Success).target_user(user_id),
+        );
+    }
+    Ok(())
+}
+
+/// Emails the user's verified addresses when they sign in from a device with
+/// no live session. Devices are told apart by `User-Agent` only, so this is a
+/// courtesy notice rather than a security control.
+async fn notify_new_login(
+    state: &AppState,
+    user: &UserRow,
+    ip: Option<IpAddr>,
+    user_agent: Option<&str>,
+    headers: &HeaderMap,
+) -> anyhow::Result<()> {
+    let user_agent = user_agent.map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+    let known: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM sessions
+        WHERE user_id = ? AND user_agent IS ? AND expires_at > datetime('now'))",
+    )
+    .bind(&user.user_id)
+    .bind(&user_agent)
+    .fetch_one(&state.db.session)
+    .await?;
+    if known {
+        return Ok(());
+    }
+
+    let locale = state
+        .mailer
+        .templates()
+        .choose_locale(user.locale.as_deref(), Some(headers));
+    let mut context = tera::Context::new();
+    context.insert("time", &Utc::now().format("%Y-%m-%d %H:%M UTC").to_string());
+    context.insert("ip", &ip.map_or_else(|| "-".to_string(), |ip| ip.to_string()));
+    context.insert("user_agent", user_agent.as_deref().unwrap_or("-"));
+    for to in email::verified_emails(state, &user.user_id).await? {
+        state
+            .mailer
+            .enqueue_template(&to, EmailKind::NewLogin, &locale, context.clone())
+            .await?;
+    }
+    Ok(())
+}
+
+/// `GET /v1/sessions/current`
+///
+/// Describes the signed-in user's role and permissions, so clients can tell
+/// which actions to offer.
+pub async fn current(session: SessionUser) -> Result<Response, AppError> {
+    let role = session.role();
+    Ok(response::success(Some(json!({
+        "user_id": session.user_id,
+        "user_level": session.user_level,
+        "role": role,
+        "permissions": role.permissions(),
+    }))))
+}
+
+/// `DELETE /v1/sessions/current`
+///
+/// Signs out by revoking the session the request was made with.
+pub async fn logout(
+    State(state): State<AppState>,
+    client_ip: Option<Extension<ClientIp>>,
+    session: SessionUser,
+) -> Result<Response, AppError> {
+    sqlx::query("DELETE FROM sessions WHERE session_id = ?")
+        .bind(&session.session_id)
+        .execute(&state.db.session)
+        .await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(&session.user_id, "auth.logout", Outcome::Success)
+            .target_user(&session.user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    let mut response = response::success(None);
+    response
+        .headers_mut()
+        .append(header::SET_COOKIE, session_cookie("", 0));
+    Ok(response)
+}
@@ -1,28 +1,83 @@
 // src/sqlite.rs
 
-use fancy_log::{log, LogLevel};
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
-use std::{fs, path::Path};
+use crate::{
+    handle,
+    logging::{log, LogLevel},
+};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePoolOptions},
+    ConnectOptions, Pool, Sqlite,
+};
+use std::{fs, path::Path, str::FromStr};
+
+/// Connection pools for each database file under `/opt/stardust/data`.
+#[derive(Clone)]
+pub struct Databases {
+    pub account: Pool<Sqlite>,
+    pub email: Pool<Sqlite>,
+    pub handle: Pool<Sqlite>,
+    pub passwd: Pool<Sqlite>,
+    pub totp: Pool<Sqlite>,
+    pub passkey: Pool<Sqlite>,
+    pub recovery: Pool<Sqlite>,
+    pub audit: Pool<Sqlite>,
+    pub session: Pool<Sqlite>,
+    pub mail: Pool<Sqlite>,
+}
+
+impl Databases {
+    /// Every pool paired with the name of its database file (without extension).
+    pub fn all(&self) -> [(&'static str, &Pool<Sqlite>); 10] {
+        [
+            ("account", &self.account),
+            ("email", &self.email),
+            ("handle", &self.handle),
+            ("passwd", &self.passwd),
+            ("totp", &self.totp),
+            ("passkey", &self.passkey),
+            ("recovery", &self.recovery),
+            ("audit", &self.audit),
+            ("session", &self.session),
+            ("mail", &self.mail),
+        ]
+    }
+}
+
+pub const MAX_CONNECTIONS: u32 = 5;
+
+pub const DATA_DIR: &str = "/opt/stardust/data";
 
-pub async fn initialize_databases() -> anyhow::Result<()> {
-    let root_dir = Path::new("/opt/stardust");
-    let data_dir = root_dir.join("data");
+/// SQLite `CURRENT_TIMESTAMP` format, used by every `DATETIME` column.
+pub const SQLITE_DATETIME: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Database files attached by `connect_attached`, by schema name.
+const ATTACHED_SCHEMAS: [&str; 7] = [
+    "email", "handle", "passwd", "totp", "passkey", "recovery", "session",
+];
+
+pub async fn initialize_databases() -> anyhow::Result<Databases> {
+    let data_dir = Path::new(DATA_DIR).to_path_buf();
 
     if !data_dir.exists() {
         fs::create_dir_all(&data_dir)?;
         log(LogLevel::Info, "Created directory: /opt/stardust/data");
     }
 
-    initialize_account_db(&data_dir).await?;
-    initialize_email_db(&data_dir).await?;
-    initialize_handle_db(&data_dir).await?;
-    initialize_passwd_db(&data_dir).await?;
-    initialize_totp_db(&data_dir).await?;
-    initialize_passkey_db(&data_dir).await?;
-    initialize_recovery_db(&data_dir).await?;
+    let databases = Databases {
+        account: initialize_account_db(&data_dir).await?,
+        email: initialize_email_db(&data_dir).await?,
+        handle: initialize_handle_db(&data_dir).await?,
+        passwd: initialize_passwd_db(&data_dir).await?,
+        totp: initialize_totp_db(&data_dir).await?,
+        passkey: initialize_passkey_db(&data_dir).await?,
+        recovery: initialize_recovery_db(&data_dir).await?,
+        audit: initialize_audit_db(&data_dir).await?,
+        session: initialize_session_db(&data_dir).await?,
+        mail: initialize_mail_db(&data_dir).await?,
+    };
 
     log(LogLevel::Info, "All databases initialized successfully.");
-    Ok(())
+    Ok(databases)
 }
 
 async fn get_pool(db_path: &Path) -> anyhow::Result<Pool<Sqlite>> {
@@ -36,12 +91,38 @@ async fn get_pool(db_path: &Path) -> anyhow::Result<Pool<Sqlite>> {
 
     let uri = format!("sqlite://{}", db_path.display());
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&uri)
+        .max_connections(MAX_CONNECTIONS)
+        .connect_with(connect_options(&uri)?)
         .await?;
     Ok(pool)
 }
 
+/// Each table lives in its own file, so references such as
+/// `email_users.user_id -> users` point across files. SQLite can only enforce
+/// foreign keys within one schema, so enforcement is turned off; integrity is
+/// kept by the application instead (see `users::purge_deleted_users`).
+fn connect_options(uri: &str) -> anyhow::Result<SqliteConnectOptions> {
+    Ok(SqliteConnectOptions::from_str(uri)?.foreign_keys(false))
+}
+
+/// Opens a standalone connection to `account.sqlite` with every other
+/// account-related file attached under its own schema name (`email.email_users`,
+/// `passwd.passwd_users`, ...), so changes spanning several files can be made
+/// in a single atomic transaction.
+pub async fn connect_attached() -> anyhow::Result<SqliteConnection> {
+    let data_dir = Path::new(DATA_DIR);
+    let uri = format!("sqlite://{}", data_dir.join("account.sqlite").display());
+    let mut conn = connect_options(&uri)?.connect().await?;
+    for schema in ATTACHED_SCHEMAS {
+        let path = data_dir.join(format!("{}.sqlite", schema));
+        sqlx::query(&format!("ATTACH DATABASE ? AS {}", schema))
+            .bind(path.display().to_string())
+            .execute(&mut conn)
+            .await?;
+    }
+    Ok(conn)
+}
+
 /// CREATE TABLE
 macro_rules! create_table {
     ($pool:expr, $table:expr, $schema:expr) => {{
@@ -50,8 +131,27 @@ macro_rules! create_table {
     }};
 }
 
+/// ALTER TABLE ADD COLUMN, skipped when the column already exists.
+/// Evaluates to whether the column was added, for one-off backfills.
+macro_rules! add_column {
+    ($pool:expr, $table:expr, $column:expr, $definition:expr) => {{
+        let sql = format!(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?)",
+            $table
+        );
+        let exists: bool = sqlx::query_scalar(&sql).bind($column).fetch_one($pool).await?;
+        let added = !exists;
+        if added {
+            let sql = format!("ALTER TABLE {} ADD COLUMN {} {}", $table, $column, $definition);
+            sqlx::query(&sql).execute($pool).await?;
+            log(LogLevel::Info, &format!("Added column {}.{}", $table, $column));
+        }
+        added
+    }};
+}
+
 /// Account
-async fn initialize_account_db(data_dir: &Path) -> anyhow::Result<()> {
+async fn initialize_account_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("account.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -66,6 +166,25 @@ async fn initialize_account_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // Soft-delete tombstone; rows are purged after the grace period.
+    add_column!(&pool, "users", "deleted_at", "DATETIME");
+
+    // Account status (see `account::AccountStatus`). `status_until` ends a
+    // suspension or lock; NULL means it lasts until lifted by an admin.
+    add_column!(
+        &pool,
+        "users",
+        "status",
+        "TEXT NOT NULL DEFAULT 'active' \
+        CHECK (status IN ('active', 'suspended', 'locked', 'pending_verification'))"
+    );
+    add_column!(&pool, "users", "status_reason", "TEXT");
+    add_column!(&pool, "users", "status_until", "DATETIME");
+    add_column!(&pool, "users", "failed_logins", "INTEGER NOT NULL DEFAULT 0");
+
+    // Preferred language for emails; NULL follows the browser's Accept-Language.
+    add_column!(&pool, "users", "locale", "TEXT");
+
     sqlx::query(
         "
         CREATE TRIGGER IF NOT EXISTS update_user_timestamp
@@ -79,11 +198,11 @@ async fn initialize_account_db(data_dir: &Path) -> anyhow::Result<()> {
     .await?;
 
     log(LogLevel::Debug, "Account database initialized.");
-    Ok(())
+    Ok(pool)
 }
 
 /// Email
-async fn initialize_email_db(data_dir: &Path) -> anyhow::Result<()> {
+pub async fn initialize_email_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("email.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -98,6 +217,87 @@ async fn initialize_email_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // Emails stay unverified until the code or link sent to them is confirmed.
+    // Addresses bound before verification existed were trusted already, so
+    // they are backfilled as verified when the column is added.
+    add_column!(&pool, "email_users", "verified_at", "DATETIME");
+    if add_column!(&pool, "email_users", "verified", "INTEGER NOT NULL DEFAULT 0") {
+        sqlx::query("UPDATE email_users SET verified = 1, verified_at = last_modified")
+            .execute(&pool)
+            .await?;
+    }
+
+    // One pending verification per email. Only hashes of the code and link
+    // token are stored. `send_count` counts sends since `window_started_at`.
+    create_table!(
+        &pool,
+        "email_verifications",
+        "
+            email TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            expires_at DATETIME NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_sent_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            send_count INTEGER NOT NULL DEFAULT 1,
+            window_started_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (email) REFERENCES email_users (email)
+        "
+    );
+
+    // Pending email sign-ins (see `email_login`). Only hashes of the link
+    // token, the code and the requesting browser's nonce are stored.
+    create_table!(
+        &pool,
+        "email_logins",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id TEXT NOT NULL,
+            email TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            code_hash TEXT NOT NULL,
+            nonce_hash TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            consumed_at DATETIME,
+            ip TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        "
+    );
+    sqlx::query("CREATE INDEX IF NOT EXISTS email_logins_nonce ON email_logins (nonce_hash)")
+        .execute(&pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS email_logins_user ON email_logins (user_id)")
+        .execute(&pool)
+        .await?;
+
+    // Email sign-in requests, for the per-address and per-IP limits. Every
+    // request is recorded, whether or not the address is bound.
+    create_table!(
+        &pool,
+        "email_login_attempts",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT NOT NULL,
+            ip TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        "
+    );
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS email_login_attempts_email
+        ON email_login_attempts (email, created_at)",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS email_login_attempts_ip
+        ON email_login_attempts (ip, created_at)",
+    )
+    .execute(&pool)
+    .await?;
+
     sqlx::query(
         "
         CREATE TRIGGER IF NOT EXISTS update_email_timestamp
@@ -111,11 +311,11 @@ async fn initialize_email_db(data_dir: &Path) -> anyhow::Result<()> {
     .await?;
 
     log(LogLevel::Debug, "Email database initialized.");
-    Ok(())
+    Ok(pool)
 }
 
 /// Handle
-async fn initialize_handle_db(data_dir: &Path) -> anyhow::Result<()> {
+async fn initialize_handle_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("handle.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -130,6 +330,39 @@ async fn initialize_handle_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // Case- and confusable-folded form of the handle (see `handle::canonical`),
+    // which is what uniqueness is enforced on.
+    add_column!(&pool, "handle_users", "handle_key", "TEXT");
+    backfill_handle_keys(&pool).await?;
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS handle_users_key ON handle_users (handle_key)",
+    )
+    .execute(&pool)
+    .await?;
+
+    // Handles a user has given up, kept so old links can be redirected and so
+    // nobody else can grab a handle right after it is released.
+    create_table!(
+        &pool,
+        "handle_history",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id TEXT NOT NULL,
+            handle TEXT NOT NULL,
+            handle_key TEXT NOT NULL,
+            claimed_at DATETIME,
+            released_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+        "
+    );
+    sqlx::query("CREATE INDEX IF NOT EXISTS handle_history_key ON handle_history (handle_key)")
+        .execute(&pool)
+        .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS handle_history_user ON handle_history (user_id, released_at)",
+    )
+    .execute(&pool)
+    .await?;
+
     sqlx::query(
         "
         CREATE TRIGGER IF NOT EXISTS update_handle_timestamp
@@ -143,11 +376,42 @@ async fn initialize_handle_db(data_dir: &Path) -> anyhow::Result<()> {
     .await?;
 
     log(LogLevel::Debug, "Handle database initialized.");
+    Ok(pool)
+}
+
+/// Computes `handle_key` for handles stored before it existed. Handles whose
+/// key is already used stay without one, and cannot sign in by handle, until
+/// an admin resolves the clash.
+async fn backfill_handle_keys(pool: &Pool<Sqlite>) -> anyhow::Result<()> {
+    let handles: Vec<String> =
+        sqlx::query_scalar("SELECT handle FROM handle_users WHERE handle_key IS NULL")
+            .fetch_all(pool)
+            .await?;
+    for handle in handles {
+        let key = handle::canonical(&handle);
+        let clash: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM handle_users WHERE handle_key = ?)")
+                .bind(&key)
+                .fetch_one(pool)
+                .await?;
+        if clash {
+            log(
+                LogLevel::Warn,
+                &format!("Handle '{}' collides with an existing handle; left unindexed.", handle),
+            );
+            continue;
+        }
+        sqlx::query("UPDATE handle_users SET handle_key = ? WHERE handle = ?")
+            .bind(&key)
+            .bind(&handle)
+            .execute(pool)
+            .await?;
+    }
     Ok(())
 }
 
 /// Passwd
-async fn initialize_passwd_db(data_dir: &Path) -> anyhow::Result<()> {
+async fn initialize_passwd_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("passwd.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -161,12 +425,56 @@ async fn initialize_passwd_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // Password reset tokens, stored hashed. A token is spent once
+    // `consumed_at` is set, by use or by a newer request.
+    create_table!(
+        &pool,
+        "password_resets",
+        "
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            consumed_at DATETIME,
+            ip TEXT
+        "
+    );
+    sqlx::query("CREATE INDEX IF NOT EXISTS password_resets_user ON password_resets (user_id)")
+        .execute(&pool)
+        .await?;
+
+    // Reset requests and failed confirmations, counted for rate limiting.
+    // `user_id` is set for requests naming a known account.
+    create_table!(
+        &pool,
+        "password_reset_attempts",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL CHECK (kind IN ('request', 'confirm')),
+            ip TEXT,
+            user_id TEXT,
+            created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+        "
+    );
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS password_reset_attempts_ip
+        ON password_reset_attempts (kind, ip, created_at)",
+    )
+    .execute(&pool)
+    .await?;
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS password_reset_attempts_user
+        ON password_reset_attempts (kind, user_id, created_at)",
+    )
+    .execute(&pool)
+    .await?;
+
     log(LogLevel::Debug, "Passwd database initialized.");
-    Ok(())
+    Ok(pool)
 }
 
 /// TOTP
-async fn initialize_totp_db(data_dir: &Path) -> anyhow::Result<()> {
+pub async fn initialize_totp_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("totp.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -179,12 +487,16 @@ async fn initialize_totp_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // Time step of the last accepted code, so a code cannot be replayed
+    // within its validity window.
+    add_column!(&pool, "totp_users", "last_used_step", "INTEGER");
+
     log(LogLevel::Debug, "TOTP database initialized.");
-    Ok(())
+    Ok(pool)
 }
 
 /// Passkey
-async fn initialize_passkey_db(data_dir: &Path) -> anyhow::Result<()> {
+pub async fn initialize_passkey_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("passkey.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -197,12 +509,18 @@ async fn initialize_passkey_db(data_dir: &Path) -> anyhow::Result<()> {
         "
     );
 
+    // `public_key` is the base64url DER SubjectPublicKeyInfo of the credential
+    // (see `passkey::StoredCredential`). `sign_count` is the authenticator's
+    // signature counter from its last assertion.
+    add_column!(&pool, "passkey_users", "credential_id", "TEXT");
+    add_column!(&pool, "passkey_users", "sign_count", "INTEGER NOT NULL DEFAULT 0");
+
     log(LogLevel::Debug, "Passkey database initialized.");
-    Ok(())
+    Ok(pool)
 }
 
 /// Recovery
-async fn initialize_recovery_db(data_dir: &Path) -> anyhow::Result<()> {
+async fn initialize_recovery_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
     let db_path = data_dir.join("recovery.sqlite");
     let pool = get_pool(&db_path).await?;
 
@@ -216,5 +534,157 @@ async fn initialize_recovery_db(data_dir: &Path) -> anyhow::Result<()> {
     );
 
     log(LogLevel::Debug, "Recovery database initialized.");
-    Ok(())
+    Ok(pool)
+}
+
+/// Audit
+async fn initialize_audit_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
+    let db_path = data_dir.join("audit.sqlite");
+    let pool = get_pool(&db_path).await?;
+
+    create_table!(
+        &pool,
+        "audit_events",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at TEXT NOT NULL,
+            actor TEXT NOT NULL,
+            action TEXT NOT NULL,
+            target_user TEXT,
+            ip TEXT,
+            request_id TEXT,
+            outcome TEXT NOT NULL,
+            prev_hash TEXT NOT NULL,
+            hash TEXT NOT NULL
+        "
+    );
+
+    // The table is append-only: reject any attempt to rewrite history through SQL.
+    for (name, event) in [
+        ("audit_events_no_update", "UPDATE"),
+        ("audit_events_no_delete", "DELETE"),
+    ] {
+        let sql = format!(
+            "CREATE TRIGGER IF NOT EXISTS {} BEFORE {} ON audit_events
+            BEGIN
+                SELECT RAISE(ABORT, 'audit_events is append-only');
+            END",
+            name, event
+        );
+        sqlx::query(&sql).execute(&pool).await?;
+    }
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS audit_events_target ON audit_events (target_user, id)")
+        .execute(&pool)
+        .await?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS audit_events_action ON audit_events (action, id)")
+        .execute(&pool)
+        .await?;
+
+    log(LogLevel::Debug, "Audit database initialized.");
+    Ok(pool)
+}
+
+/// Session
+async fn initialize_session_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
+    let db_path = data_dir.join("session.sqlite");
+    let pool = get_pool(&db_path).await?;
+
+    // `session_id` is the SHA-256 of the token handed to the client; the
+    // token itself is never stored.
+    create_table!(
+        &pool,
+        "sessions",
+        "
+            session_id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            last_seen_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            ip TEXT,
+            user_agent TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        "
+    );
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS sessions_user ON sessions (user_id)")
+        .execute(&pool)
+        .await?;
+
+    // Sign-ins waiting for a second factor (see `mfa`). Keyed by the SHA-256
+    // of the challenge token; `factors` is a comma-separated list of what the
+    // user may answer with, and `passkey_challenge` the WebAuthn challenge.
+    create_table!(
+        &pool,
+        "mfa_challenges",
+        "
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            factors TEXT NOT NULL,
+            passkey_challenge TEXT,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            ip TEXT,
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        "
+    );
+    sqlx::query("CREATE INDEX IF NOT EXISTS mfa_challenges_user ON mfa_challenges (user_id)")
+        .execute(&pool)
+        .await?;
+
+    // Challenges handed out for usernameless passkey sign-in, which are not
+    // tied to a user until the assertion names one. Each is spent on use.
+    create_table!(
+        &pool,
+        "passkey_challenges",
+        "
+            challenge TEXT PRIMARY KEY,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            expires_at DATETIME NOT NULL,
+            ip TEXT
+        "
+    );
+    sqlx::query("CREATE INDEX IF NOT EXISTS passkey_challenges_ip ON passkey_challenges (ip)")
+        .execute(&pool)
+        .await?;
+
+    log(LogLevel::Debug, "Session database initialized.");
+    Ok(pool)
+}
+
+/// Mail
+pub async fn initialize_mail_db(data_dir: &Path) -> anyhow::Result<Pool<Sqlite>> {
+    let db_path = data_dir.join("mail.sqlite");
+    let pool = get_pool(&db_path).await?;
+
+    // Outbound queue drained by `mailer::Mailer`. `status` is `pending`,
+    // `sent`, `failed` or `expired`; pending rows are retried at
+    // `next_attempt_at`.
+    create_table!(
+        &pool,
+        "mail_outbox",
+        "
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            recipient TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            text_body TEXT NOT NULL,
+            html_body TEXT,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_error TEXT,
+            sent_at DATETIME
+        "
+    );
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS mail_outbox_due ON mail_outbox (status, next_attempt_at)",
+    )
+    .execute(&pool)
+    .await?;
+
+    log(LogLevel::Debug, "Mail database initialized.");
+    Ok(pool)
 }
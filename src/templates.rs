@@ -0,0 +1,222 @@
+// src/templates.rs
+
+use crate::logging::{log, LogLevel};
+use anyhow::{bail, Context as _};
+use axum::http::{header, HeaderMap};
+use rust_embed::RustEmbed;
+use std::{collections::BTreeSet, env, fs, path::Path};
+use tera::{Context, Tera};
+
+/// Default email templates, extracted to `TEMPLATES_DIR` on startup.
+///
+/// Each locale has its own directory (`en/`, `zh/`, ...) holding three files
+/// per email: `{name}.subject.txt`, `{name}.txt` and `{name}.html`. HTML
+/// parts extend the shared `base.html`.
+#[derive(RustEmbed)]
+#[folder = "templates/"]
+pub struct TemplateAssets;
+
+pub const TEMPLATES_DIR: &str = "/opt/stardust/etc/templates";
+
+/// Locale used when nothing better matches. It must provide every template.
+const DEFAULT_LOCALE: &str = "en";
+
+/// Product name passed to every template as `app_name`, unless `APP_NAME` is set.
+const DEFAULT_APP_NAME: &str = "Stardust";
+
+/// The transactional emails stardust sends.
+#[derive(Clone, Copy, Debug)]
+pub enum EmailKind {
+    Verification,
+    PasswordReset,
+    NewLogin,
+    TotpDisabled,
+    LoginLink,
+}
+
+impl EmailKind {
+    const ALL: [EmailKind; 5] = [
+        EmailKind::Verification,
+        EmailKind::PasswordReset,
+        EmailKind::NewLogin,
+        EmailKind::TotpDisabled,
+        EmailKind::LoginLink,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            EmailKind::Verification => "verification",
+            EmailKind::PasswordReset => "password_reset",
+            EmailKind::NewLogin => "new_login",
+            EmailKind::TotpDisabled => "totp_disabled",
+            EmailKind::LoginLink => "login_link",
+        }
+    }
+}
+
+/// File suffixes making up one email.
+const PARTS: [&str; 3] = ["subject.txt", "txt", "html"];
+
+/// Extracts embedded templates that are missing on disk. Existing files are
+/// never overwritten, so operators can edit them to override the defaults.
+pub fn setup_templates_directory() -> anyhow::Result<()> {
+    let templates_dir = Path::new(TEMPLATES_DIR);
+    if !templates_dir.exists() {
+        fs::create_dir_all(templates_dir)?;
+        log(LogLevel::Info, &format!("Created directory: {}", TEMPLATES_DIR));
+    }
+
+    for file_path in TemplateAssets::iter() {
+        let target_path = templates_dir.join(file_path.as_ref());
+        if !target_path.exists()
+            && let Some(file_data) = TemplateAssets::get(&file_path)
+        {
+            if let Some(parent) = target_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&target_path, file_data.data.as_ref())?;
+            log(LogLevel::Debug, &format!("Extracted: {}", target_path.display()));
+        }
+    }
+    Ok(())
+}
+
+/// A rendered email, ready to be queued.
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// The email templates loaded from `TEMPLATES_DIR`.
+pub struct EmailTemplates {
+    tera: Tera,
+    locales: BTreeSet<String>,
+    app_name: String,
+}
+
+impl EmailTemplates {
+    /// Parses every template and checks that the default locale is complete,
+    /// so a broken override fails at startup rather than when mail is sent.
+    pub fn load() -> anyhow::Result<Self> {
+        let tera = Tera::new(&format!("{}/**/*", TEMPLATES_DIR))
+            .context("Failed to parse email templates")?;
+        let locales: BTreeSet<String> = tera
+            .get_template_names()
+            .filter_map(|name| name.split_once('/').map(|(locale, _)| locale.to_string()))
+            .collect();
+
+        for kind in EmailKind::ALL {
+            for part in PARTS {
+                let name = format!("{}/{}.{}", DEFAULT_LOCALE, kind.name(), part);
+                if !tera.get_template_names().any(|n| n == name) {
+                    bail!("Missing email template {}/{}", TEMPLATES_DIR, name);
+                }
+            }
+        }
+        log(
+            LogLevel::Info,
+            &format!(
+                "Loaded email templates for locales: {}",
+                locales.iter().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        );
+
+        Ok(Self {
+            tera,
+            locales,
+            app_name: env::var("APP_NAME").unwrap_or_else(|_| DEFAULT_APP_NAME.to_string()),
+        })
+    }
+
+    /// Maps a language tag to an available locale: the full tag (`zh-tw`)
+    /// if it has its own directory, else its primary language (`zh`).
+    pub fn match_locale(&self, tag: &str) -> Option<String> {
+        let tag = tag.trim().to_lowercase().replace('_', "-");
+        if self.locales.contains(&tag) {
+            return Some(tag);
+        }
+        let primary = tag.split('-').next()?;
+        self.locales.contains(primary).then(|| primary.to_string())
+    }
+
+    pub fn locales(&self) -> impl Iterator<Item = &str> {
+        self.locales.iter().map(String::as_str)
+    }
+
+    /// Picks the locale for an email: the user's stored preference, else the
+    /// best match from `Accept-Language`, else the default.
+    pub fn choose_locale(&self, stored: Option<&str>, headers: Option<&HeaderMap>) -> String {
+        if let Some(locale) = stored.and_then(|tag| self.match_locale(tag)) {
+            return locale;
+        }
+        let accept = headers
+            .and_then(|headers| headers.get(header::ACCEPT_LANGUAGE))
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        parse_accept_language(accept)
+            .into_iter()
+            .find_map(|tag| self.match_locale(&tag))
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+    }
+
+    /// Renders all parts of an email. Parts missing from `locale` fall back
+    /// to the default locale one by one.
+    pub fn render(
+        &self,
+        kind: EmailKind,
+        locale: &str,
+        mut context: Context,
+    ) -> anyhow::Result<RenderedEmail> {
+        context.insert("app_name", &self.app_name);
+        context.insert("locale", locale);
+        let render_part = |part: &str| -> anyhow::Result<String> {
+            let localized = format!("{}/{}.{}", locale, kind.name(), part);
+            let name = if self.tera.get_template_names().any(|n| n == localized) {
+                localized
+            } else {
+                format!("{}/{}.{}", DEFAULT_LOCALE, kind.name(), part)
+            };
+            self.tera
+                .render(&name, &context)
+                .with_context(|| format!("Failed to render email template {}", name))
+        };
+
+        Ok(RenderedEmail {
+            subject: render_part("subject.txt")?.trim().to_string(),
+            text: render_part("txt")?,
+            html: render_part("html")?,
+        })
+    }
+}
+
+#[cfg(test)]
+impl EmailTemplates {
+    /// No templates at all, for tests that queue plain emails.
+    pub fn empty() -> Self {
+        Self {
+            tera: Tera::default(),
+            locales: BTreeSet::new(),
+            app_name: DEFAULT_APP_NAME.to_string(),
+        }
+    }
+}
+
+/// Language tags from an `Accept-Language` header, most preferred first.
+/// Tags with `q=0` and the `*` wildcard are dropped.
+fn parse_accept_language(value: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f32)> = value
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let tag = params.next()?.trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then(|| (tag.to_string(), quality))
+        })
+        .collect();
+    // Stable, so equal weights keep the client's order.
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
@@ -0,0 +1,763 @@
+// src/users.rs
+
+use crate::{
+    account::{self, AccountStatus},
+    audit::{Audit, AuditEvent, Outcome},
+    auth::Caller,
+    error::{AppError, FieldError},
+    extract::{AppJson, AppQuery},
+    handle,
+    logging::{log, LogLevel},
+    password,
+    rate_limiting::ClientIp,
+    rbac::{self, perm, Authorized, Role},
+    response, session,
+    sqlite::{self, Databases, SQLITE_DATETIME},
+    AppState,
+};
+use axum::{
+    extract::{Extension, Path, State},
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Connection, QueryBuilder, Sqlite};
+use std::{collections::BTreeSet, env, time::Duration};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Upper bound on user IDs collected by an email/handle prefix search.
+const MAX_SEARCH_MATCHES: i64 = 1000;
+
+pub const MAX_USER_LEVEL: i64 = 255;
+
+/// Days a soft-deleted user is kept before being purged, unless `USER_DELETE_GRACE_DAYS` is set.
+const DEFAULT_DELETE_GRACE_DAYS: i64 = 30;
+/// Seconds between purge runs, unless `USER_PURGE_INTERVAL_SECS` is set.
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 3600;
+
+/// Tables holding per-user rows in the attached files, cleaned up on purge.
+/// `handle.handle_history` is deliberately kept: see `purge_deleted_users`.
+const USER_TABLES: [&str; 12] = [
+    "email.email_verifications",
+    "email.email_logins",
+    "email.email_users",
+    "handle.handle_users",
+    "passwd.passwd_users",
+    "passwd.password_resets",
+    "passwd.password_reset_attempts",
+    "totp.totp_users",
+    "passkey.passkey_users",
+    "recovery.recovery_users",
+    "session.sessions",
+    "session.mfa_challenges",
+];
+
+/// Longest accepted suspension reason, in characters.
+const MAX_REASON_LEN: usize = 500;
+
+const USER_COLUMNS: &str = "user_id, user_level, created_at, last_modified, deleted_at, \
+    status, status_reason, status_until, failed_logins, locale";
+
+/// A row of `users`.
+#[derive(Debug, sqlx::FromRow)]
+pub struct UserRow {
+    pub user_id: String,
+    pub user_level: i64,
+    pub created_at: Option<String>,
+    pub last_modified: Option<String>,
+    pub deleted_at: Option<String>,
+    pub status: String,
+    pub status_reason: Option<String>,
+    pub status_until: Option<String>,
+    pub failed_logins: i64,
+    pub locale: Option<String>,
+}
+
+/// The `users` row as listed, with the status resolved.
+fn user_summary(user: &UserRow) -> serde_json::Value {
+    json!({
+        "user_id": user.user_id,
+        "user_level": user.user_level,
+        "role": Role::from_level(user.user_level),
+        "created_at": user.created_at,
+        "last_modified": user.last_modified,
+        "deleted_at": user.deleted_at,
+        "status": account::current_status(user),
+    })
+}
+
+#[derive(Serialize, Debug, sqlx::FromRow)]
+struct EmailRow {
+    email: String,
+    verified: bool,
+    verified_at: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Which authentication factors a user has enrolled.
+#[derive(Serialize, Debug)]
+struct Factors {
+    password: bool,
+    totp: bool,
+    passkey: bool,
+    recovery: bool,
+}
+
+/// Fetches a user that has not been deleted.
+pub async fn fetch_user(db: &Databases, user_id: &str) -> Result<UserRow, AppError> {
+    let sql = format!(
+        "SELECT {} FROM users WHERE user_id = ? AND deleted_at IS NULL",
+        USER_COLUMNS
+    );
+    sqlx::query_as::<_, UserRow>(&sql)
+        .bind(user_id)
+        .fetch_optional(&db.account)
+        .await?
+        .ok_or_else(|| user_not_found(user_id))
+}
+
+pub fn user_not_found(user_id: &str) -> AppError {
+    AppError::NotFound {
+        code: "user.not_found",
+        message: format!("User {} does not exist.", user_id),
+    }
+}
+
+/// Returns whether `table` in `pool` holds a non-null `column` for the user.
+pub async fn has_factor(
+    pool: &sqlx::Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    user_id: &str,
+) -> Result<bool, AppError> {
+    let sql = format!(
+        "SELECT EXISTS(SELECT 1 FROM {} WHERE user_id = ? AND {} IS NOT NULL)",
+        table, column
+    );
+    let exists: bool = sqlx::query_scalar(&sql)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(exists)
+}
+
+/// Builds the full user document: the `users` row joined with its emails,
+/// handle and enrolled factors from the other database files.
+pub async fn user_document(db: &Databases, user: UserRow) -> Result<serde_json::Value, AppError> {
+    let emails: Vec<EmailRow> = sqlx::query_as(
+        "SELECT email, verified, verified_at, last_modified FROM email_users
+        WHERE user_id = ? ORDER BY email",
+    )
+    .bind(&user.user_id)
+    .fetch_all(&db.email)
+    .await?;
+
+    let handle: Option<String> =
+        sqlx::query_scalar("SELECT handle FROM handle_users WHERE user_id = ? LIMIT 1")
+            .bind(&user.user_id)
+            .fetch_optional(&db.handle)
+            .await?;
+    let previous_handles: Vec<(String, String)> = sqlx::query_as(
+        "SELECT handle, released_at FROM handle_history WHERE user_id = ? ORDER BY released_at DESC, id DESC",
+    )
+    .bind(&user.user_id)
+    .fetch_all(&db.handle)
+    .await?;
+
+    let factors = Factors {
+        password: has_factor(&db.passwd, "passwd_users", "password_hash", &user.user_id).await?,
+        totp: has_factor(&db.totp, "totp_users", "totp_secret", &user.user_id).await?,
+        passkey: has_factor(&db.passkey, "passkey_users", "public_key", &user.user_id).await?,
+        recovery: has_factor(&db.recovery, "recovery_users", "recovery_key", &user.user_id).await?,
+    };
+
+    Ok(json!({
+        "status": account::current_status(&user),
+        "user_id": user.user_id,
+        "user_level": user.user_level,
+        "role": Role::from_level(user.user_level),
+        "created_at": user.created_at,
+        "last_modified": user.last_modified,
+        "failed_logins": user.failed_logins,
+        "locale": user.locale,
+        "emails": emails,
+        "handle": handle,
+        "previous_handles": previous_handles
+            .into_iter()
+            .map(|(handle, released_at)| json!({ "handle": handle, "released_at": released_at }))
+            .collect::<Vec<_>>(),
+        "factors": factors,
+    }))
+}
+
+/// `GET /v1/users/{id}`
+pub async fn get_user(
+    State(state): State<AppState>,
+    _auth: Authorized<perm::UsersRead>,
+    Path(user_id): Path<String>,
+) -> Result<Response, AppError> {
+    let user = fetch_user(&state.db, &user_id).await?;
+    Ok(response::success(Some(user_document(&state.db, user).await?)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateUser {
+    user_level: i64,
+}
+
+/// `PATCH /v1/users/{id}`
+pub async fn update_user(
+    State(state): State<AppState>,
+    auth: Authorized<perm::UsersWrite>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<UpdateUser>,
+) -> Result<Response, AppError> {
+    if !(0..=MAX_USER_LEVEL).contains(&body.user_level) {
+        return Err(AppError::Validation(vec![FieldError::new(
+            "user_level",
+            "out_of_range",
+            format!("Must be between 0 and {}.", MAX_USER_LEVEL),
+        )]));
+    }
+    let target = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_outranks(&auth.caller, target.user_level)?;
+    rbac::ensure_outranks(&auth.caller, body.user_level)?;
+
+    let result = sqlx::query(
+        "UPDATE users SET user_level = ? WHERE user_id = ? AND deleted_at IS NULL",
+    )
+    .bind(body.user_level)
+    .bind(&user_id)
+    .execute(&state.db.account)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found(&user_id));
+    }
+
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "user.level_change", Outcome::Success)
+            .target_user(&user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    let user = fetch_user(&state.db, &user_id).await?;
+    Ok(response::success(Some(user_document(&state.db, user).await?)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SuspendUser {
+    reason: String,
+    /// RFC 3339 timestamp at which the suspension ends; omit for an indefinite one.
+    until: Option<String>,
+}
+
+/// `POST /v1/users/{id}/suspend`
+///
+/// Suspends the user and revokes all their sessions. Suspending an already
+/// suspended or locked user replaces the reason and end time.
+pub async fn suspend_user(
+    State(state): State<AppState>,
+    auth: Authorized<perm::UsersSuspend>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<SuspendUser>,
+) -> Result<Response, AppError> {
+    let mut errors = Vec::new();
+    let reason = body.reason.trim();
+    if reason.is_empty() {
+        errors.push(FieldError::new("reason", "required", "A reason is required."));
+    } else if reason.chars().count() > MAX_REASON_LEN {
+        errors.push(FieldError::new(
+            "reason",
+            "too_long",
+            format!("Must be at most {} characters.", MAX_REASON_LEN),
+        ));
+    }
+    let until = match body.until.as_deref().map(DateTime::parse_from_rfc3339) {
+        Some(Ok(t)) if t.with_timezone(&Utc) > Utc::now() => {
+            Some(t.with_timezone(&Utc).format(SQLITE_DATETIME).to_string())
+        }
+        Some(Ok(_)) => {
+            errors.push(FieldError::new("until", "in_past", "Must be in the future."));
+            None
+        }
+        Some(Err(_)) => {
+            errors.push(FieldError::new(
+                "until",
+                "invalid_timestamp",
+                "Expected an RFC 3339 timestamp.",
+            ));
+            None
+        }
+        None => None,
+    };
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    let target = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_outranks(&auth.caller, target.user_level)?;
+
+    let result = sqlx::query(
+        "UPDATE users SET status = 'suspended', status_reason = ?, status_until = ?, failed_logins = 0
+        WHERE user_id = ? AND deleted_at IS NULL",
+    )
+    .bind(reason)
+    .bind(&until)
+    .bind(&user_id)
+    .execute(&state.db.account)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found(&user_id));
+    }
+    session::revoke_user_sessions(&state.db, &user_id).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "user.suspend", Outcome::Success)
+            .target_user(&user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    let user = fetch_user(&state.db, &user_id).await?;
+    Ok(response::success(Some(user_document(&state.db, user).await?)))
+}
+
+/// `POST /v1/users/{id}/unsuspend`
+///
+/// Lifts a suspension or a failed-login lock and resets the failure counter.
+pub async fn unsuspend_user(
+    State(state): State<AppState>,
+    auth: Authorized<perm::UsersSuspend>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Result<Response, AppError> {
+    let user = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_outranks(&auth.caller, user.user_level)?;
+    if !matches!(
+        account::current_status(&user).status,
+        AccountStatus::Suspended | AccountStatus::Locked
+    ) {
+        return Err(AppError::Conflict {
+            code: "user.not_suspended",
+            message: format!("User {} is neither suspended nor locked.", user_id),
+        });
+    }
+
+    sqlx::query(
+        "UPDATE users SET status = 'active', status_reason = NULL, status_until = NULL, failed_logins = 0
+        WHERE user_id = ? AND status IN ('suspended', 'locked')",
+    )
+    .bind(&user_id)
+    .execute(&state.db.account)
+    .await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "user.unsuspend", Outcome::Success)
+            .target_user(&user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    let user = fetch_user(&state.db, &user_id).await?;
+    Ok(response::success(Some(user_document(&state.db, user).await?)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportPasswordHash {
+    hash: String,
+    salt: Option<String>,
+}
+
+/// `PUT /v1/users/{id}/password-hash`
+///
+/// Stores a password hash carried over from another system, in any scheme
+/// sign-in can verify. It is replaced with Argon2id the first time the user
+/// signs in with it. Only the API token may import, since the hash stands in
+/// for a password nobody has to know; the user's sessions are revoked.
+pub async fn import_password_hash(
+    State(state): State<AppState>,
+    auth: Authorized<perm::UsersWrite>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+    AppJson(body): AppJson<ImportPasswordHash>,
+) -> Result<Response, AppError> {
+    if !matches!(auth.caller, Caller::Token { .. }) {
+        return Err(AppError::Forbidden {
+            code: "auth.token_required",
+            message: "Password hashes can only be imported with the API token.".to_string(),
+        });
+    }
+    let hash = body.hash.trim();
+    let salt = body.salt.as_deref().filter(|salt| !salt.is_empty());
+    let Some(scheme) = password::identify(hash, salt) else {
+        return Err(AppError::Validation(vec![FieldError::new(
+            "hash",
+            "unsupported_format",
+            "Expected an Argon2id, bcrypt, scrypt, PBKDF2-SHA256 or salted SHA-256 hash.",
+        )]));
+    };
+    if !password::import_cost_acceptable(scheme, hash) {
+        return Err(AppError::Validation(vec![FieldError::new(
+            "hash",
+            "cost_too_high",
+            "The hash's cost parameters exceed what sign-in will verify.",
+        )]));
+    }
+    let target = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_outranks(&auth.caller, target.user_level)?;
+
+    sqlx::query(
+        "INSERT INTO passwd_users (user_id, password_hash, salt) VALUES (?, ?, ?)
+        ON CONFLICT (user_id) DO UPDATE SET password_hash = excluded.password_hash, salt = excluded.salt",
+    )
+    .bind(&user_id)
+    .bind(hash)
+    .bind(salt)
+    .execute(&state.db.passwd)
+    .await?;
+    session::revoke_user_sessions(&state.db, &user_id).await?;
+
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "password.import", Outcome::Success)
+            .target_user(&user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    Ok(response::success(Some(json!({
+        "user_id": user_id,
+        "scheme": scheme.as_str(),
+    }))))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListUsers {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    level: Option<i64>,
+    /// Current account status, e.g. `suspended`.
+    status: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    /// Prefix of a bound email or handle.
+    q: Option<String>,
+    /// Also list soft-deleted users awaiting purge.
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+/// Parses an RFC 3339 timestamp or a plain `YYYY-MM-DD` date into SQLite's
+/// `CURRENT_TIMESTAMP` format, so it compares correctly against stored values.
+fn parse_created_filter(field: &str, value: &str) -> Result<String, FieldError> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Ok(t.with_timezone(&Utc).format(SQLITE_DATETIME).to_string());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(format!("{} 00:00:00", date));
+    }
+    Err(FieldError::new(
+        field,
+        "invalid_timestamp",
+        "Expected an RFC 3339 timestamp or a YYYY-MM-DD date.",
+    ))
+}
+
+/// Cursors are opaque to clients: the sort key of the last row of a page.
+fn encode_cursor(created_at: &str, user_id: &str) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}\n{}", created_at, user_id))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(String, String)> {
+    let raw = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (created_at, user_id) = raw.split_once('\n')?;
+    Some((created_at.to_string(), user_id.to_string()))
+}
+
+/// Escapes `LIKE` wildcards so a search term only ever matches as a literal prefix.
+fn like_prefix(term: &str) -> String {
+    let escaped = term
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+/// Finds users whose email or handle starts with `term`.
+async fn search_user_ids(db: &Databases, term: &str) -> Result<BTreeSet<String>, AppError> {
+    let pattern = like_prefix(term);
+    let mut ids: BTreeSet<String> = BTreeSet::new();
+
+    let by_email: Vec<String> = sqlx::query_scalar(
+        "SELECT user_id FROM email_users WHERE email LIKE ? ESCAPE '\\' LIMIT ?",
+    )
+    .bind(&pattern)
+    .bind(MAX_SEARCH_MATCHES)
+    .fetch_all(&db.email)
+    .await?;
+    ids.extend(by_email);
+
+    let by_handle: Vec<String> = sqlx::query_scalar(
+        "SELECT user_id FROM handle_users WHERE handle LIKE ? ESCAPE '\\' LIMIT ?",
+    )
+    .bind(&pattern)
+    .bind(MAX_SEARCH_MATCHES)
+    .fetch_all(&db.handle)
+    .await?;
+    ids.extend(by_handle);
+
+    Ok(ids)
+}
+
+/// `GET /v1/users`
+///
+/// Lists users newest first. Pass the returned `next_cursor` as `cursor` to
+/// fetch the next page; it is `null` on the last page.
+pub async fn list_users(
+    State(state): State<AppState>,
+    _auth: Authorized<perm::UsersRead>,
+    AppQuery(query): AppQuery<ListUsers>,
+) -> Result<Response, AppError> {
+    let mut errors = Vec::new();
+    let created_after = query
+        .created_after
+        .as_deref()
+        .map(|v| parse_created_filter("created_after", v))
+        .transpose()
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            None
+        });
+    let created_before = query
+        .created_before
+        .as_deref()
+        .map(|v| parse_created_filter("created_before", v))
+        .transpose()
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            None
+        });
+    let status = match query.status.as_deref() {
+        Some(raw) => match AccountStatus::parse(raw) {
+            Some(status) => Some(status),
+            None => {
+                errors.push(FieldError::new("status", "invalid_status", "Unknown account status."));
+                None
+            }
+        },
+        None => None,
+    };
+    let cursor = match query.cursor.as_deref() {
+        Some(raw) => match decode_cursor(raw) {
+            Some(cursor) => Some(cursor),
+            None => {
+                errors.push(FieldError::new("cursor", "invalid_cursor", "Cursor is malformed."));
+                None
+            }
+        },
+        None => None,
+    };
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let search_ids = match query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        Some(term) => {
+            let ids = search_user_ids(&state.db, term).await?;
+            if ids.is_empty() {
+                return Ok(response::success(Some(json!({
+                    "users": [],
+                    "next_cursor": null,
+                }))));
+            }
+            Some(ids)
+        }
+        None => None,
+    };
+
+    let mut builder =
+        QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM users WHERE 1 = 1", USER_COLUMNS));
+    if !query.include_deleted {
+        builder.push(" AND deleted_at IS NULL");
+    }
+    if let Some(level) = query.level {
+        builder.push(" AND user_level = ").push_bind(level);
+    }
+    if let Some(status) = status {
+        builder.push(" AND ").push(status.filter_sql());
+    }
+    if let Some(after) = created_after {
+        builder.push(" AND created_at >= ").push_bind(after);
+    }
+    if let Some(before) = created_before {
+        builder.push(" AND created_at < ").push_bind(before);
+    }
+    if let Some(ids) = search_ids {
+        builder.push(" AND user_id IN (");
+        let mut separated = builder.separated(", ");
+        for id in ids {
+            separated.push_bind(id);
+        }
+        separated.push_unseparated(")");
+    }
+    if let Some((created_at, user_id)) = cursor {
+        builder
+            .push(" AND (COALESCE(created_at, ''), user_id) < (")
+            .push_bind(created_at)
+            .push(", ")
+            .push_bind(user_id)
+            .push(")");
+    }
+    builder
+        .push(" ORDER BY COALESCE(created_at, '') DESC, user_id DESC LIMIT ")
+        .push_bind(limit);
+
+    let users: Vec<UserRow> = builder
+        .build_query_as()
+        .fetch_all(&state.db.account)
+        .await?;
+    let next_cursor = match users.last() {
+        Some(last) if users.len() as i64 == limit => Some(encode_cursor(
+            last.created_at.as_deref().unwrap_or(""),
+            &last.user_id,
+        )),
+        _ => None,
+    };
+
+    Ok(response::success(Some(json!({
+        "users": users.iter().map(user_summary).collect::<Vec<_>>(),
+        "next_cursor": next_cursor,
+    }))))
+}
+
+fn delete_grace_days() -> i64 {
+    env::var("USER_DELETE_GRACE_DAYS")
+        .ok()
+        .and_then(|val| val.trim().parse::<i64>().ok())
+        .filter(|days| *days >= 0)
+        .unwrap_or(DEFAULT_DELETE_GRACE_DAYS)
+}
+
+/// `DELETE /v1/users/{id}`
+///
+/// Soft-deletes the user. The row is tombstoned immediately, which hides it
+/// from every lookup, and the user is purged from all database files by the
+/// background job once the grace period has passed.
+pub async fn delete_user(
+    State(state): State<AppState>,
+    auth: Authorized<perm::UsersDelete>,
+    Path(user_id): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> Result<Response, AppError> {
+    let target = fetch_user(&state.db, &user_id).await?;
+    rbac::ensure_outranks(&auth.caller, target.user_level)?;
+
+    let result = sqlx::query(
+        "UPDATE users SET deleted_at = CURRENT_TIMESTAMP WHERE user_id = ? AND deleted_at IS NULL",
+    )
+    .bind(&user_id)
+    .execute(&state.db.account)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(user_not_found(&user_id));
+    }
+
+    state.audit.spawn_record(
+        AuditEvent::new(auth.caller.actor(), "user.delete", Outcome::Success)
+            .target_user(&user_id)
+            .ip(client_ip.map(|ip| ip.0 .0)),
+    );
+
+    let grace_days = delete_grace_days();
+    let purge_after: Option<String> = sqlx::query_scalar(
+        "SELECT datetime(deleted_at, '+' || ? || ' days') FROM users WHERE user_id = ?",
+    )
+    .bind(grace_days)
+    .bind(&user_id)
+    .fetch_one(&state.db.account)
+    .await?;
+
+    Ok(response::success(Some(json!({
+        "user_id": user_id,
+        "deleted": true,
+        "purge_after": purge_after,
+    }))))
+}
+
+/// Hard-deletes every user whose grace period has passed, removing their rows
+/// from all database files in one transaction per user.
+///
+/// The user's current handle is moved to the handle history rather than
+/// deleted, and the history is kept, so the release cooldown keeps their
+/// handles from being claimed by an impersonator straight after the purge.
+async fn purge_deleted_users(audit: &Audit, grace_days: i64) -> anyhow::Result<usize> {
+    let mut conn = sqlite::connect_attached().await?;
+    let expired: Vec<String> = sqlx::query_scalar(
+        "SELECT user_id FROM users
+        WHERE deleted_at IS NOT NULL AND deleted_at <= datetime('now', '-' || ? || ' days')",
+    )
+    .bind(grace_days)
+    .fetch_all(&mut conn)
+    .await?;
+
+    for user_id in &expired {
+        let mut tx = conn.begin().await?;
+        let handles: Vec<(String, Option<String>, Option<String>)> = sqlx::query_as(
+            "SELECT handle, handle_key, last_modified FROM handle.handle_users WHERE user_id = ?",
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (handle, key, claimed_at) in handles {
+            sqlx::query(
+                "INSERT INTO handle.handle_history (user_id, handle, handle_key, claimed_at)
+                VALUES (?, ?, ?, ?)",
+            )
+            .bind(user_id)
+            .bind(&handle)
+            .bind(key.unwrap_or_else(|| handle::canonical(&handle)))
+            .bind(claimed_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+        for table in USER_TABLES {
+            sqlx::query(&format!("DELETE FROM {} WHERE user_id = ?", table))
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("DELETE FROM users WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        audit
+            .record(AuditEvent::new("system", "user.purge", Outcome::Success).target_user(user_id))
+            .await?;
+    }
+    Ok(expired.len())
+}
+
+/// Starts the background job that purges soft-deleted users.
+pub fn spawn_purge_job(audit: Audit) {
+    let interval_secs = env::var("USER_PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|val| val.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_PURGE_INTERVAL_SECS);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            match purge_deleted_users(&audit, delete_grace_days()).await {
+                Ok(0) => {}
+                Ok(count) => log(LogLevel::Info, &format!("Purged {} deleted user(s).", count)),
+                Err(e) => log(LogLevel::Error, &format!("Failed to purge deleted users: {:#}", e)),
+            }
+        }
+    });
+}